@@ -1,36 +1,164 @@
-use std::io::Write;
-
-use clap::{Parser, Args};
-use inquire::{self, Confirm, Text};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::{Parser, Args, Subcommand, CommandFactory, FromArgMatches};
+use inquire::{self, Confirm, InquireError, Text};
 use inquire::error::InquireResult;
 use inquire::ui::RenderConfig;
 use toml::{self, Value};
 
-use crate::error::*;
-use crate::*;
+use dev::dotenvx;
+use dev::error::*;
+use dev::keys::{self, Audit, ConfigKeys};
+use dev::scan::{self, Scanner};
+use dev::secrets::{self, Secrets};
+use dev::sops;
+use dev::*;
 
 
 /// The missing tool for 12 factor development environments.
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    #[arg(global = true, short, long, default_value = "local")]
-    environment: String,
+    /// The environment to use, which defaults to `default_environment` from
+    /// the config, or "local".
+    #[arg(global = true, short, long)]
+    environment: Option<String>,
+
+    /// Skip confirmation prompts for protected environments.
+    #[arg(global = true, short, long)]
+    yes: bool,
+
+    /// Print the full chain of causes when an error occurs, and which
+    /// project root was used. There's no short flag, so `-v` still reaches
+    /// psql in `dev psql`.
+    #[arg(global = true, long)]
+    verbose: bool,
+
+    /// The project's root directory, instead of finding it from the current
+    /// directory. Defaults to DEV_REPO if it's set.
+    #[arg(global = true, long)]
+    repo: Option<PathBuf>,
+
+    /// Use a package from the monorepo's [packages], by name. There's no
+    /// short flag, so `-p` still reaches psql as its port in `dev psql`.
+    #[arg(global = true, long)]
+    package: Option<String>,
+
+    /// Replace secret values in the output of commands with their names, so
+    /// they don't end up in logs.
+    #[arg(global = true, long)]
+    redact: bool,
 
     #[command(subcommand)]
     command: SubCommand,
 }
 
 impl Cli {
-    pub fn run(&self) -> Result<()> {
-        let repo = Repo::new()?;
-        let environment = repo.get_environment(self.environment.clone());
+    pub fn run(&self) -> Result<i32> {
+        let mut repo = open_repo(self.repo.clone(), self.package.as_deref())?;
+        repo.set_assume_yes(self.yes);
+        repo.set_redact(self.redact);
+        if self.verbose {
+            eprintln!("Using project root {:?} ({})", repo.path(), repo.source());
+        }
+        for name in shadowed_tasks(&repo) {
+            eprintln!("The '{}' task has the same name as `dev {}`, so it can only be run with `dev task {}`.", name, name, name);
+        }
+        let name = self.environment.clone()
+            .unwrap_or_else(|| repo.default_environment().into());
+        let environment = repo.get_environment(name);
         (&self.command).run(&repo, &environment)
     }
 }
 
+/// Run the command line, returning the exit code instead of exiting, so it
+/// can also be run inside another process, such as the Python interpreter.
+pub fn run<I, T>(args: I) -> i32
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+
+    // Tasks are listed in the help, so the project's config is loaded on a
+    // best effort basis before parsing. Errors are reported when it's loaded
+    // again to run the command.
+    let mut command = Cli::command();
+    let (repo_path, package) = scan_repo_args(&args);
+    if let Some(tasks) = open_repo(repo_path, package.as_deref()).ok().and_then(|repo| tasks_help(&repo)) {
+        command = command.after_help(tasks);
+    }
+    let cli = match command.try_get_matches_from(&args)
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+    {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return e.exit_code();
+        },
+    };
+
+    // Any secrets decrypted by the command would be written to a core dump.
+    // This is only done here, as it changes the limit for the whole process.
+    secrets::disable_core_dumps();
+
+    match cli.run() {
+        Ok(code) => code,
+        Err(e) => {
+            let arg0 = args.first()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "dev".into());
+            eprint!("{}: ", arg0);
+            if cli.verbose {
+                let _ = write_error_chain(&e, &mut std::io::stderr());
+            } else {
+                eprintln!("{}", e);
+            }
+            if let Some(hint) = e.hint() {
+                eprintln!("hint: {}", hint);
+            }
+            1
+        },
+    }
+}
+
+/// Load the project at `--repo`, or the one containing the current
+/// directory, switching to one of its packages if `--package` is given.
+fn open_repo(repo_path: Option<PathBuf>, package: Option<&str>) -> Result<Repo> {
+    let repo = Repo::open(repo_path)?;
+    match package {
+        Some(package) => repo.package(package),
+        None => Ok(repo),
+    }
+}
+
+/// Find the `--repo` and `--package` options before the arguments are
+/// parsed, so the help can list the tasks of the project they select.
+fn scan_repo_args(args: &[OsString]) -> (Option<PathBuf>, Option<String>) {
+    let mut repo_path = None;
+    let mut package = None;
+    let mut args = args.iter().skip(1).filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        match name {
+            "--" => break,
+            "--repo" => repo_path = value.or_else(|| args.next()).map(PathBuf::from),
+            "--package" => package = value.or_else(|| args.next()).map(String::from),
+            _ => {},
+        }
+    }
+    (repo_path, package)
+}
+
 trait Runnable {
-    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<()>;
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32>;
 }
 
 // dev ...
@@ -42,6 +170,8 @@ enum SubCommand {
     Start(StartCommand),
     /// Run all CI checks enabled for this project.
     Check(CheckCommand),
+    /// Search the project's files for secret values in plaintext.
+    Scan(ScanCommand),
     /// Initial dev tool files in a git repo.
     Init(InitCommand),
     /// Interact with environment variables in an environment.
@@ -49,19 +179,43 @@ enum SubCommand {
         #[command(subcommand)]
         command: ConfigCommand,
     },
-    /// Connect to the postgresql server for this environment.
-    Psql(PsqlCommand),
+    /// Connect to the database for this environment.
+    #[command(visible_alias = "psql")]
+    Db(DbCommand),
+    /// Manage the environments in this project.
+    Env {
+        #[command(subcommand)]
+        command: EnvCommand,
+    },
+    /// Manage who can decrypt each environment.
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Run a task from the config's [commands.tasks].
+    Task(TaskCommand),
+    /// Tasks can also be run directly, as `dev <name> [args]`.
+    #[command(external_subcommand)]
+    External(Vec<String>),
 }
 
 impl Runnable for &SubCommand {
-    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<()> {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
         match self {
             SubCommand::Run(cmd) => cmd.run(repo, environment),
             SubCommand::Config { command } => command.run(repo, environment),
             SubCommand::Start(cmd) => cmd.run(repo, environment),
             SubCommand::Check(cmd) => cmd.run(repo, environment),
+            SubCommand::Scan(cmd) => cmd.run(repo, environment),
             SubCommand::Init(cmd) => cmd.run(repo, environment),
-            SubCommand::Psql(cmd) => cmd.run(repo, environment),
+            SubCommand::Db(cmd) => cmd.run(repo, environment),
+            SubCommand::Env { command } => command.run(repo, environment),
+            SubCommand::Keys { command } => command.run(repo, environment),
+            SubCommand::Task(cmd) => cmd.run(repo, environment),
+            SubCommand::External(args) => {
+                let (name, args) = args.split_first().expect("external subcommands have a name");
+                run_task(repo, environment, name, args)
+            },
         }
     }
 }
@@ -77,32 +231,152 @@ struct RunCommand {
 }
 
 impl Runnable for &RunCommand {
-    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<()> {
-        let mut args: Vec<&str> = self.args.iter()
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        environment.run_hook(Hook::PreRun, Vec::new())?;
+        let args: Vec<&str> = self.args.iter()
             .map(String::as_str)
             .collect();
-        if let Some(commands) = &repo.config.commands {
-            if let Some(shell) = &commands.shell {
-                args.insert(0, self.command.as_str());
-                args.insert(0, "--");
-                args.insert(0, shell);
-                args.insert(0, "-ce");
-                return environment.exec("bash", args);
-            }
+        exec_in_shell(repo, environment, &self.command, args)
+    }
+}
+
+/// Run a command with the environment's variables, inside the configured
+/// shell wrapper if there is one.
+fn exec_in_shell<'a>(repo: &'a Repo, environment: &Environment<'_>, command: &'a str, mut args: Vec<&'a str>) -> Result<i32> {
+    if let Some(commands) = &repo.config().commands {
+        if let Some(shell) = &commands.shell {
+            args.insert(0, command);
+            args.insert(0, "--");
+            args.insert(0, shell);
+            args.insert(0, "-ce");
+            return environment.exec("bash", args);
+        }
+    }
+    environment.exec(command, args)
+}
+
+// dev task <name> [args]
+#[derive(Args)]
+struct TaskCommand {
+    /// List the configured tasks, with their descriptions.
+    #[arg(short, long, conflicts_with = "name")]
+    list: bool,
+    /// The name of the task to run.
+    #[arg(required_unless_present = "list")]
+    name: Option<String>,
+    /// Any arguments to be passed into the task, as "$@".
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+impl TaskCommand {
+    fn write_list<W: Write>(&self, repo: &Repo, out: &mut W) -> std::io::Result<()> {
+        for line in task_lines(repo) {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Runnable for &TaskCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        match &self.name {
+            Some(name) if !self.list => run_task(repo, environment, name, &self.args),
+            _ => {
+                self.write_list(repo, &mut std::io::stdout())
+                    .map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+                Ok(0)
+            },
         }
-        environment.exec(self.command.as_str(), args)
     }
 }
 
+/// Run a task by name, passing it any extra arguments. The task's script is
+/// run by bash, named after the task, inside the shell wrapper.
+fn run_task(repo: &Repo, environment: &Environment<'_>, name: &str, args: &[String]) -> Result<i32> {
+    let task = repo.config().commands.as_ref()
+        .and_then(|commands| commands.tasks.as_ref())
+        .and_then(|tasks| tasks.get(name))
+        .ok_or_else(|| AppError::UnknownTask(name.into()))?;
+
+    environment.confirm()?;
+    environment.run_hook(Hook::PreRun, vec![("DEV_TASK".into(), name.into())])?;
+    let mut task_args = vec!["-ce", task.command(), name];
+    task_args.extend(args.iter().map(String::as_str));
+    exec_in_shell(repo, environment, "bash", task_args)
+}
+
+/// Each task's name and description, or the first line of its command if it
+/// has none, aligned the same way for `dev task --list` and the help.
+fn task_lines(repo: &Repo) -> Vec<String> {
+    let tasks = repo.config().commands.as_ref().and_then(|commands| commands.tasks.as_ref());
+    let Some(tasks) = tasks else {
+        return Vec::new();
+    };
+
+    let width = tasks.keys().map(|name| name.chars().count()).max().unwrap_or(0);
+    tasks.iter()
+        .map(|(name, task)| {
+            let description = task.description()
+                .unwrap_or_else(|| task.command().lines().next().unwrap_or_default());
+            format!("{:width$}  {}", name, description, width = width).trim_end().to_string()
+        })
+        .collect()
+}
+
+/// A "Tasks:" section for the help, listing the project's tasks.
+fn tasks_help(repo: &Repo) -> Option<String> {
+    let lines = task_lines(repo);
+    if lines.is_empty() {
+        return None;
+    }
+
+    let mut help = String::from("Tasks:");
+    for line in lines {
+        help.push_str("\n  ");
+        help.push_str(&line);
+    }
+    Some(help)
+}
+
+/// Tasks that can't be run as `dev <name>`, because one of dev's own commands
+/// has the same name.
+fn shadowed_tasks(repo: &Repo) -> Vec<String> {
+    let tasks = repo.config().commands.as_ref().and_then(|commands| commands.tasks.as_ref());
+    let Some(tasks) = tasks else {
+        return Vec::new();
+    };
+
+    let command = Cli::command();
+    let builtins: Vec<&str> = command.get_subcommands()
+        .flat_map(|subcommand| std::iter::once(subcommand.get_name()).chain(subcommand.get_all_aliases()))
+        .chain(["help"])
+        .collect();
+    tasks.keys()
+        .filter(|name| builtins.contains(&name.as_str()))
+        .cloned()
+        .collect()
+}
+
 // dev start
 #[derive(Args)]
 struct StartCommand;
 
 impl Runnable for &StartCommand {
-    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<()> {
-        if let Some(commands) = &repo.config.commands {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        if let Some(commands) = &repo.config().commands {
             if let Some(start) = &commands.start {
-                return environment.exec("bash", vec!["-ce", &start]);
+                environment.confirm()?;
+                environment.run_hook(Hook::PreStart, Vec::new())?;
+                let status = environment.run_with_envs("bash", vec!["-ce", start], Vec::new())?;
+                let code = exit_code(status);
+                // The start command has already finished, so its exit code
+                // is kept when cleaning up after it fails.
+                if let Err(err) = environment.run_hook(Hook::PostStart, vec![("DEV_EXIT_CODE".into(), code.to_string())]) {
+                    eprintln!("{}", err);
+                }
+                return Ok(code);
             }
         }
         Err(AppError::ConfigMissing("commands.start".into()))
@@ -111,19 +385,39 @@ impl Runnable for &StartCommand {
 
 // dev check
 #[derive(Args)]
-struct CheckCommand;
+struct CheckCommand {
+    /// Run the checks of every package in the monorepo, in its directory.
+    #[arg(long)]
+    all_packages: bool,
+}
 
-impl Runnable for &CheckCommand {
-    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<()> {
-        if let Some(commands) = &repo.config.commands {
+impl CheckCommand {
+    fn run_checks(&self, repo: &Repo, environment: &str, current_dir: Option<&Path>) -> Result<()> {
+        if let Some(commands) = &repo.config().commands {
             if let Some(checks) = &commands.checks {
+                // Checks don't otherwise need the environment, so it's only
+                // decrypted when there are values to redact.
+                let redactor = match repo.redact() {
+                    true => Some(repo.get_environment(environment.into()).redactor()?),
+                    false => None,
+                };
                 for (name, check) in checks {
                     eprintln!("Running {} check...", name);
                     let mut command = Command::new("bash");
                     command.arg("-ce");
                     command.arg(check);
-
-                    let result = match command.status() {
+                    if let Some(current_dir) = current_dir {
+                        command.current_dir(current_dir);
+                    }
+                    if redactor.is_some() {
+                        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+                    }
+
+                    let status = command.spawn().and_then(|mut child| match &redactor {
+                        Some(redactor) => redactor.wait(&mut child),
+                        None => child.wait(),
+                    });
+                    let result = match status {
                         Ok(status) if status.success() => Ok(()),
                         Ok(status) => Err(CommandError::FailedError {
                             status,
@@ -140,6 +434,90 @@ impl Runnable for &CheckCommand {
         }
         Err(AppError::ConfigMissing("commands.checks".into()))
     }
+
+    fn run_all_packages(&self, repo: &Repo, environment: &str) -> Result<()> {
+        let mut failed = Vec::new();
+        for name in repo.packages()?.keys() {
+            eprintln!("Checking package {}...", name);
+            let package = repo.package(name)?;
+            match self.run_checks(&package, environment, Some(package.path())) {
+                Ok(()) => {},
+                Err(AppError::ConfigMissing(_)) => eprintln!("No checks configured for {}.", name),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    failed.push(name.clone());
+                },
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(AppError::PackageChecksFailed(failed));
+        }
+        eprintln!("All packages passed!");
+        Ok(())
+    }
+}
+
+impl Runnable for &CheckCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        if self.all_packages {
+            self.run_all_packages(repo, environment.name())?;
+        } else {
+            // Checks for a package selected with -p run in its directory, as
+            // they would with --all-packages.
+            let current_dir = (repo.source() == RepoSource::Package).then(|| repo.path());
+            self.run_checks(repo, environment.name(), current_dir)?;
+        }
+        Ok(0)
+    }
+}
+
+// dev scan [paths]
+#[derive(Args)]
+struct ScanCommand {
+    /// Files or directories to search, instead of the whole project.
+    paths: Vec<PathBuf>,
+    /// Fail if any environment can't be decrypted, instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+}
+
+impl Runnable for &ScanCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let mut environments = Vec::new();
+        let mut skipped = Vec::new();
+        for name in repo.environment_names().map_err(AgeDecryptError::Io)? {
+            match repo.get_environment(name.clone()).redactor() {
+                Ok(redactor) => environments.push((name, redactor)),
+                Err(err) => {
+                    eprintln!("Skipping the '{}' environment, which can't be decrypted: {}", name, err);
+                    skipped.push(name);
+                },
+            }
+        }
+        // Without any values to search for, a scan would always pass.
+        if environments.is_empty() {
+            return Err(AppError::NoEnvironmentsScanned);
+        }
+        if self.strict && !skipped.is_empty() {
+            return Err(AppError::EnvironmentsSkipped(skipped));
+        }
+        let allow = repo.config().scan.as_ref()
+            .map(|scan| scan.allow.clone())
+            .unwrap_or_default();
+        let scanner = Scanner::new(environments, allow);
+
+        let findings = scan::scan_repo(repo, &scanner, &self.paths)?;
+        let mut stdout = std::io::stdout();
+        for finding in &findings {
+            writeln!(stdout, "{}", finding).map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+        }
+        if !findings.is_empty() {
+            return Err(AppError::SecretsFound(findings.len()));
+        }
+        eprintln!("No secrets found.");
+        Ok(0)
+    }
 }
 
 // dev init
@@ -147,13 +525,14 @@ impl Runnable for &CheckCommand {
 struct InitCommand;
 
 impl InitCommand {
-    fn ensure_dir(&self, path: PathBuf) {
-        if let Err(e) = std::fs::create_dir(path) {
+    fn ensure_dir(&self, path: PathBuf) -> Result<()> {
+        if let Err(e) = std::fs::create_dir(&path) {
             match e.kind() {
                 std::io::ErrorKind::AlreadyExists => {},
-                _ => panic!("{:?}", e),
+                _ => return Err(AppError::IoError(path, e)),
             }
         };
+        Ok(())
     }
 
     fn prompt_for_ssh_keys(&self) -> InquireResult<Vec<String>> {
@@ -259,12 +638,13 @@ impl InitCommand {
 }
 
 impl Runnable for &InitCommand {
-    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<()> {
-        let dev_dir = repo.repo_path.join(".dev");
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let dev_dir = repo.path().join(".dev");
         let config_path = dev_dir.join("config.toml");
-        if std::fs::exists(&config_path).unwrap() {
-            eprintln!("Refusing to initialize dev config, {:?} already exists.", config_path);
-            std::process::exit(1);
+        let exists = std::fs::exists(&config_path)
+            .map_err(|e| AppError::IoError(config_path.clone(), e))?;
+        if exists {
+            return Err(AppError::ConfigExists(config_path));
         }
 
         let render_config = RenderConfig::default();
@@ -273,26 +653,28 @@ impl Runnable for &InitCommand {
         eprintln!("Welcome to the dev setup process.");
 
         // Create the .dev directory
-        self.ensure_dir(dev_dir);
+        self.ensure_dir(dev_dir)?;
 
         // Prompt for details to put in the config file.
-        let keys = self.prompt_for_ssh_keys().unwrap();
-        let checks = self.prompt_for_check_commands().unwrap();
-        let shell = self.prompt_for_shell_command().unwrap();
-        let start = self.prompt_for_start_command().unwrap();
+        let keys = self.prompt_for_ssh_keys()?;
+        let checks = self.prompt_for_check_commands()?;
+        let shell = self.prompt_for_shell_command()?;
+        let start = self.prompt_for_start_command()?;
 
         // Write settings to the config.toml file
         let config = Config {
-            commands: Some(Commands { shell, start, checks }),
+            commands: Some(Commands { shell, start, checks, tasks: None }),
             keys: Some(BTreeMap::from([
                 ("default".into(), keys),
             ])),
+            ..Config::default()
         };
-        let config = toml::to_string_pretty(&config).unwrap();
-        std::fs::write(&config_path, config).unwrap();
+        let config = toml::to_string_pretty(&config).map_err(AppError::ConfigWriteError)?;
+        std::fs::write(&config_path, config)
+            .map_err(|e| AppError::IoError(config_path.clone(), e))?;
         eprintln!("Config written to {:?}.", config_path);
 
-        Ok(())
+        Ok(0)
     }
 }
 
@@ -303,13 +685,19 @@ enum ConfigCommand {
     Export(ConfigExportCommand),
     /// Decrypt and open the environment variable file in your default editor.
     Edit(ConfigEditCommand),
+    /// Import environment variables from an existing file.
+    Import(ConfigImportCommand),
+    /// Show which config files were loaded.
+    Paths(ConfigPathsCommand),
 }
 
 impl Runnable for &ConfigCommand {
-    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<()> {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
         match self {
             ConfigCommand::Export(cmd) => cmd.run(repo, environment),
             ConfigCommand::Edit(cmd) => cmd.run(repo, environment),
+            ConfigCommand::Import(cmd) => cmd.run(repo, environment),
+            ConfigCommand::Paths(cmd) => cmd.run(repo, environment),
         }
     }
 }
@@ -322,49 +710,142 @@ struct ConfigExportCommand {
 }
 
 impl Runnable for &ConfigExportCommand {
-    fn run(self, _repo: &Repo, environment: &Environment<'_>) -> Result<()> {
+    fn run(self, _repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        let stdout = |e: std::io::Error| AppError::IoError("<stdout>".into(), e);
         match self.format {
             ConfigExportFormat::Raw => {
-                ConfigExportCommand::format_raw(environment, &mut std::io::stdout())
+                let plaintext = environment.decrypt()?;
+                ConfigExportCommand::format_raw(&plaintext, &mut std::io::stdout()).map_err(stdout)?;
             },
             ConfigExportFormat::Json => {
-                ConfigExportCommand::format_json(environment, &mut std::io::stdout())
+                let values = environment.values()?;
+                ConfigExportCommand::format_json(&values, &mut std::io::stdout()).map_err(stdout)?;
             },
             ConfigExportFormat::Docker => {
-                ConfigExportCommand::format_docker(environment, &mut std::io::stdout())
+                let values = environment.string_values()?;
+                ConfigExportCommand::format_docker(&values, &mut std::io::stdout()).map_err(stdout)?;
+            },
+            ConfigExportFormat::Systemd => {
+                let values = environment.string_values()?;
+                ConfigExportCommand::format_systemd(&values, &mut std::io::stdout()).map_err(stdout)?;
+            },
+            ConfigExportFormat::Github => {
+                let values = environment.string_values()?;
+                // Masks must be written to the step's stdout, while variables
+                // are appended to the file GitHub Actions provides, when
+                // running inside a workflow.
+                match std::env::var_os("GITHUB_ENV") {
+                    Some(path) => {
+                        let path = PathBuf::from(path);
+                        let mut file = std::fs::OpenOptions::new()
+                            .append(true)
+                            .create(true)
+                            .open(&path)
+                            .map_err(|e| AppError::IoError(path.clone(), e))?;
+                        ConfigExportCommand::format_github(&values, &mut file, &mut std::io::stdout())
+                            .map_err(|e| AppError::IoError(path, e))?;
+                    },
+                    None => {
+                        ConfigExportCommand::format_github(&values, &mut std::io::stdout(), &mut std::io::stdout())
+                            .map_err(stdout)?;
+                    },
+                }
+            },
+            ConfigExportFormat::Terraform => {
+                let values = environment.values()?;
+                ConfigExportCommand::format_terraform(&values, &mut std::io::stdout()).map_err(stdout)?;
             },
         }
+        Ok(0)
     }
 }
 
 impl ConfigExportCommand {
-    fn format_raw<W: Write>(environment: &Environment<'_>, out: &mut W) -> Result<()> {
-        let mut file = environment.decrypt()?;
-        std::io::copy(&mut file, out).unwrap();
-        Ok(())
+    fn format_raw<W: Write>(plaintext: &[u8], out: &mut W) -> std::io::Result<()> {
+        out.write_all(plaintext)
     }
 
-    fn format_json<W: Write>(environment: &Environment<'_>, out: &mut W) -> Result<()> {
-        let values = environment.values()?;
-        serde_json::to_writer_pretty(out, &values).unwrap();
+    fn format_json<W: Write>(values: &Secrets<Value>, out: &mut W) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(out, values)?;
         Ok(())
     }
 
-    fn format_docker<W: Write>(environment: &Environment<'_>, out: &mut W) -> Result<()> {
-        for (key, value) in environment.values()? {
-            let value = match value {
-                Value::String(value) => value,
-                value => serde_json::to_string(&value).unwrap(),
-            };
+    fn format_docker<W: Write>(values: &Secrets<String>, out: &mut W) -> std::io::Result<()> {
+        for (key, value) in values {
             // Docker env files don't support newlines in environment
             // variable values. We replace them with spaces to attempt
             // to allow it to still work if the use case doesn't require
             // the newlines.
             let value = value.replace("\n", " ");
-            writeln!(out, "{}={}", key, value).unwrap();
+            writeln!(out, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+
+    fn format_systemd<W: Write>(values: &Secrets<String>, out: &mut W) -> std::io::Result<()> {
+        for (key, value) in values {
+            // Within double quotes, systemd only treats a backslash as an
+            // escape before one of these characters, and keeps newlines as
+            // part of the value.
+            let mut escaped = String::with_capacity(value.len());
+            for c in value.chars() {
+                if matches!(c, '"' | '\\' | '`' | '$') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            writeln!(out, "{}=\"{}\"", key, escaped)?;
+        }
+        Ok(())
+    }
+
+    fn format_github<W: Write, M: Write>(values: &Secrets<String>, out: &mut W, mask: &mut M) -> std::io::Result<()> {
+        for (key, value) in values {
+            // GitHub only masks single lines, so each line of a multiline
+            // value needs to be masked separately.
+            for line in value.lines().filter(|line| !line.trim().is_empty()) {
+                let line = line
+                    .replace("%", "%25")
+                    .replace("\r", "%0D");
+                writeln!(mask, "::add-mask::{}", line)?;
+            }
+
+            // The heredoc delimiter can't appear as a line in the value.
+            let mut delimiter = format!("EOF_{}", key);
+            while value.lines().any(|line| line == delimiter) {
+                delimiter.push('_');
+            }
+            writeln!(out, "{}<<{}", key, delimiter)?;
+            writeln!(out, "{}", value)?;
+            writeln!(out, "{}", delimiter)?;
         }
         Ok(())
     }
+
+    fn format_terraform<W: Write>(values: &Secrets<Value>, out: &mut W) -> std::io::Result<()> {
+        let values: serde_json::Map<String, serde_json::Value> = values
+            .iter()
+            .map(|(key, value)| (key.clone(), Self::terraform_value(value.clone())))
+            .collect();
+        serde_json::to_writer_pretty(&mut *out, &values)?;
+        writeln!(out)
+    }
+
+    fn terraform_value(value: Value) -> serde_json::Value {
+        match value {
+            // Terraform has no date types, so any TOML datetimes are passed
+            // through as strings.
+            Value::Datetime(value) => serde_json::Value::String(value.to_string()),
+            Value::Array(values) => values.into_iter()
+                .map(Self::terraform_value)
+                .collect(),
+            Value::Table(values) => values.into_iter()
+                .map(|(key, value)| (key, Self::terraform_value(value)))
+                .collect(),
+            value => serde_json::to_value(value).unwrap(),
+        }
+    }
 }
 
 // dev config edit ...
@@ -372,8 +853,279 @@ impl ConfigExportCommand {
 struct ConfigEditCommand;
 
 impl Runnable for &ConfigEditCommand {
-    fn run(self, _repo: &Repo, environment: &Environment<'_>) -> Result<()> {
-        environment.edit()
+    fn run(self, _repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        environment.edit()?;
+        Ok(0)
+    }
+}
+
+// dev config paths
+#[derive(Args)]
+struct ConfigPathsCommand;
+
+impl ConfigPathsCommand {
+    fn write_paths<W: Write>(&self, repo: &Repo, out: &mut W) -> std::io::Result<()> {
+        for file in repo.config_files() {
+            let status = if file.loaded { "" } else { ", not found" };
+            writeln!(out, "{} ({}{})", file.path.display(), file.kind, status)?;
+        }
+        Ok(())
+    }
+}
+
+impl Runnable for &ConfigPathsCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        self.write_paths(repo, &mut std::io::stdout())
+            .map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+        Ok(0)
+    }
+}
+
+// dev config import <file> ...
+#[derive(Args)]
+struct ConfigImportCommand {
+    /// The file to import environment variables from.
+    file: PathBuf,
+    /// The format of the file. Guessed from the file extension if omitted.
+    #[arg(short, long, value_enum)]
+    format: Option<ConfigImportFormat>,
+    /// Replace all existing variables with the ones in the file.
+    #[arg(long)]
+    replace: bool,
+    /// Overwrite and delete the plaintext file after importing it.
+    #[arg(long)]
+    shred: bool,
+}
+
+impl Runnable for &ConfigImportCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        let import_error = |message: String| AppError::ImportError(self.file.clone(), message);
+
+        let content = std::fs::read_to_string(&self.file)
+            .map_err(|e| import_error(e.to_string()))?;
+        let format = self.format.unwrap_or_else(|| ConfigImportFormat::from_path(&self.file));
+        let imported = ConfigImportCommand::parse(format, &content)
+            .map_err(import_error)?;
+
+        let old = environment.values()?;
+        let new = if self.replace {
+            Secrets::from(imported)
+        } else {
+            let mut new = old.clone();
+            new.extend(imported);
+            new
+        };
+
+        let changes = ConfigImportCommand::write_diff(&old, &new, &mut std::io::stderr())
+            .map_err(|e| AppError::IoError("<stderr>".into(), e))?;
+        if changes == 0 {
+            eprintln!("No changes to import.");
+        } else {
+            let confirmed = repo.assume_yes() || Confirm::new(&format!("Import {} change(s) into the '{}' environment?", changes, environment.name()))
+                .with_default(true)
+                .prompt()?;
+            if !confirmed {
+                eprintln!("Import cancelled.");
+                return Ok(0);
+            }
+
+            environment.set_values(&new)?;
+            eprintln!("Imported {} change(s) into the '{}' environment.", changes, environment.name());
+        }
+
+        if self.shred {
+            ConfigImportCommand::shred(&self.file)
+                .map_err(|e| import_error(e.to_string()))?;
+            eprintln!("Shredded {:?}.", self.file);
+        }
+
+        Ok(0)
+    }
+}
+
+impl ConfigImportCommand {
+    fn parse(format: ConfigImportFormat, content: &str) -> std::result::Result<BTreeMap<String, Value>, String> {
+        match format {
+            ConfigImportFormat::Dotenv => Self::parse_dotenv(content),
+            ConfigImportFormat::Docker => Self::parse_docker(content),
+            ConfigImportFormat::Json => Self::parse_json(content),
+            ConfigImportFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn validate_key(key: &str) -> std::result::Result<(), String> {
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("invalid variable name '{}'", key));
+        }
+        Ok(())
+    }
+
+    /// Parse a `.env` file, as understood by most dotenv libraries. Values may
+    /// be single quoted (literal), double quoted (with backslash escapes), or
+    /// unquoted with an optional trailing comment.
+    fn parse_dotenv(content: &str) -> std::result::Result<BTreeMap<String, Value>, String> {
+        let mut values = BTreeMap::new();
+        let mut lines = content.lines().enumerate();
+
+        while let Some((index, line)) = lines.next() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| format!("line {}: expected KEY=value", index + 1))?;
+            let key = key.trim();
+            Self::validate_key(key).map_err(|e| format!("line {}: {}", index + 1, e))?;
+
+            let value = value.trim_start();
+            let value = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    // Quoted values may continue over multiple lines, until
+                    // the closing quote is found.
+                    let mut raw = value[1..].to_string();
+                    loop {
+                        if let Some(end) = Self::find_closing_quote(&raw, quote) {
+                            let rest = raw[end + 1..].trim();
+                            if !rest.is_empty() && !rest.starts_with('#') {
+                                return Err(format!("line {}: unexpected characters after closing quote", index + 1));
+                            }
+                            raw.truncate(end);
+                            break;
+                        }
+                        match lines.next() {
+                            Some((_, next)) => {
+                                raw.push('\n');
+                                raw.push_str(next);
+                            },
+                            None => return Err(format!("line {}: missing closing quote", index + 1)),
+                        }
+                    }
+                    if quote == '"' {
+                        Self::unescape(&raw)
+                    } else {
+                        raw
+                    }
+                },
+                _ => match value.find(" #") {
+                    Some(comment) => value[..comment].trim_end().to_string(),
+                    None => value.trim_end().to_string(),
+                },
+            };
+
+            values.insert(key.to_string(), Value::String(value));
+        }
+
+        Ok(values)
+    }
+
+    fn find_closing_quote(value: &str, quote: char) -> Option<usize> {
+        let mut escaped = false;
+        for (index, c) in value.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && quote == '"' {
+                escaped = true;
+            } else if c == quote {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn unescape(value: &str) -> String {
+        let mut result = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('t') => result.push('\t'),
+                Some(c @ ('"' | '\\' | '$' | '`')) => result.push(c),
+                Some(c) => {
+                    result.push('\\');
+                    result.push(c);
+                },
+                None => result.push('\\'),
+            }
+        }
+        result
+    }
+
+    /// Parse a docker `--env-file`, where everything after the `=` is taken
+    /// literally, and a bare variable name is copied from the current
+    /// environment.
+    fn parse_docker(content: &str) -> std::result::Result<BTreeMap<String, Value>, String> {
+        let mut values = BTreeMap::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim_start();
+            if line.trim_end().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key, Some(value.to_string())),
+                None => (line.trim_end(), std::env::var(line.trim_end()).ok()),
+            };
+            Self::validate_key(key).map_err(|e| format!("line {}: {}", index + 1, e))?;
+            if let Some(value) = value {
+                values.insert(key.to_string(), Value::String(value));
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn parse_json(content: &str) -> std::result::Result<BTreeMap<String, Value>, String> {
+        let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(content)
+            .map_err(|e| e.to_string())?;
+
+        let mut result = BTreeMap::new();
+        for (key, value) in values {
+            // TOML has no null, so treat it as the variable being unset.
+            if value.is_null() {
+                continue;
+            }
+            let value = Value::try_from(value)
+                .map_err(|e| format!("{}: {}", key, e))?;
+            result.insert(key, value);
+        }
+
+        Ok(result)
+    }
+
+    /// Write a summary of changed variable names, without any values, returning
+    /// the number of changes.
+    fn write_diff<W: Write>(old: &BTreeMap<String, Value>, new: &BTreeMap<String, Value>, out: &mut W) -> std::io::Result<usize> {
+        let keys: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        let mut changes = 0;
+        for key in keys {
+            let marker = match (old.get(key), new.get(key)) {
+                (None, Some(_)) => "+",
+                (Some(_), None) => "-",
+                (Some(old), Some(new)) if old != new => "~",
+                _ => continue,
+            };
+            writeln!(out, "{} {}", marker, key)?;
+            changes += 1;
+        }
+        Ok(changes)
+    }
+
+    /// Overwrite a file's content with zeros before removing it, so the
+    /// plaintext doesn't stay on disk.
+    fn shred(path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let len = file.metadata()?.len();
+        std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+        file.sync_all()?;
+        std::fs::remove_file(path)
     }
 }
 
@@ -383,40 +1135,716 @@ enum ConfigExportFormat {
     Raw,
     Json,
     Docker,
+    /// A systemd `EnvironmentFile`.
+    Systemd,
+    /// A GitHub Actions `$GITHUB_ENV` file, with `::add-mask::` commands.
+    Github,
+    /// A Terraform `.tfvars.json` file.
+    Terraform,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum ConfigImportFormat {
+    Dotenv,
+    Json,
+    Docker,
+    Toml,
+}
+
+impl ConfigImportFormat {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::Json,
+            Some("toml") => Self::Toml,
+            _ => Self::Dotenv,
+        }
+    }
 }
 
-// dev psql
+// dev db
 #[derive(Args)]
-struct PsqlCommand {
-    /// Any arguments to be passed into the psql command.
+#[command(args_conflicts_with_subcommands = true)]
+struct DbCommand {
+    #[command(subcommand)]
+    command: Option<DbSubCommand>,
+    #[command(flatten)]
+    url: DbUrlArgs,
+    /// Start a read-only session, preferring <URL_VAR>_READONLY if set.
+    #[arg(long, conflicts_with = "read_write")]
+    read_only: bool,
+    /// Start a read-write session, even if the environment defaults to
+    /// read-only.
+    #[arg(long)]
+    read_write: bool,
+    /// Any arguments to be passed into the database client.
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<String>,
 }
 
-impl Runnable for &PsqlCommand {
-    fn run(self, _repo: &Repo, environment: &Environment<'_>) -> Result<()> {
-        let mut args: Vec<&str> = self.args.iter()
+impl Runnable for &DbCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        match &self.command {
+            Some(DbSubCommand::Dump(cmd)) => return cmd.run(repo, environment),
+            Some(DbSubCommand::Restore(cmd)) => return cmd.run(repo, environment),
+            Some(DbSubCommand::Copy(cmd)) => return cmd.run(repo, environment),
+            None => {},
+        }
+
+        environment.confirm()?;
+        let read_only = self.read_only || (environment.config().read_only && !self.read_write);
+        let client = self.url.client(repo, environment, read_only)?;
+        let mut args: Vec<&str> = client.args.iter()
             .map(String::as_str)
             .collect();
-        args.insert(0, "--");
-        args.insert(0, "exec psql \"${DATABASE_URL}\" \"$@\"");
-        args.insert(0, "-ce");
-        environment.exec("bash", args)
+        args.extend(self.args.iter().map(String::as_str));
+        environment.exec_with_envs(client.program, args, client.envs)
+    }
+}
+
+#[derive(Args)]
+struct DbUrlArgs {
+    /// The environment variable containing the database URL. Defaults to
+    /// `database.url_var` in the config, or DATABASE_URL.
+    #[arg(long)]
+    url_var: Option<String>,
+}
+
+impl DbUrlArgs {
+    fn client(&self, repo: &Repo, environment: &Environment<'_>, read_only: bool) -> Result<db::Client> {
+        let url_var = self.url_var.as_deref()
+            .or_else(|| repo.config().database.as_ref()?.url_var.as_deref())
+            .unwrap_or("DATABASE_URL");
+        let values = environment.string_values()?;
+        db::Client::new(&values, url_var, read_only)
+    }
+}
+
+// dev db ...
+#[derive(Subcommand)]
+enum DbSubCommand {
+    /// Dump the environment's PostgreSQL database.
+    Dump(DbDumpCommand),
+    /// Restore a dump into the environment's PostgreSQL database, replacing
+    /// any existing objects.
+    Restore(DbRestoreCommand),
+    /// Copy one environment's PostgreSQL database into another's.
+    Copy(DbCopyCommand),
+}
+
+// dev db dump [-o file]
+#[derive(Args)]
+struct DbDumpCommand {
+    #[command(flatten)]
+    url: DbUrlArgs,
+    /// The file to write the dump to, instead of stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl Runnable for &DbDumpCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        // Dumps only need to read, so use a read-only session where possible.
+        let client = self.url.client(repo, environment, true)?;
+        client.dump(self.output.as_deref())?;
+        Ok(0)
+    }
+}
+
+// dev db restore <file>
+#[derive(Args)]
+struct DbRestoreCommand {
+    #[command(flatten)]
+    url: DbUrlArgs,
+    /// The dump file to restore, as created by `dev db dump`.
+    file: PathBuf,
+}
+
+impl Runnable for &DbRestoreCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        environment.confirm()?;
+        let client = self.url.client(repo, environment, false)?;
+        client.restore(&self.file)?;
+        Ok(0)
+    }
+}
+
+// dev db copy --from <env> --to <env>
+#[derive(Args)]
+struct DbCopyCommand {
+    #[command(flatten)]
+    url: DbUrlArgs,
+    /// The environment to copy the database from.
+    #[arg(long)]
+    from: String,
+    /// The environment to copy the database into.
+    #[arg(long)]
+    to: String,
+}
+
+impl Runnable for &DbCopyCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let source = repo.get_environment(self.from.clone());
+        let destination = repo.get_environment(self.to.clone());
+        source.confirm()?;
+        destination.confirm()?;
+
+        let source = self.url.client(repo, &source, true)?;
+        let destination = self.url.client(repo, &destination, false)?;
+        db::Client::copy(&source, &destination)?;
+        eprintln!("Copied the '{}' database into '{}'.", self.from, self.to);
+        Ok(0)
+    }
+}
+
+// dev env ...
+#[derive(Subcommand)]
+enum EnvCommand {
+    /// List all environments, and who can decrypt them.
+    List(EnvListCommand),
+    /// Create a new environment with no variables.
+    Create(EnvCreateCommand),
+    /// Copy an environment, re-encrypting it with the destination's keys.
+    Copy(EnvCopyCommand),
+    /// Rename an environment, re-encrypting it with the destination's keys.
+    Rename(EnvRenameCommand),
+    /// Delete an environment.
+    Delete(EnvDeleteCommand),
+    /// Create an environment from a file encrypted by another tool.
+    Migrate(EnvMigrateCommand),
+}
+
+impl Runnable for &EnvCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        match self {
+            EnvCommand::List(cmd) => cmd.run(repo, environment),
+            EnvCommand::Create(cmd) => cmd.run(repo, environment),
+            EnvCommand::Copy(cmd) => cmd.run(repo, environment),
+            EnvCommand::Rename(cmd) => cmd.run(repo, environment),
+            EnvCommand::Delete(cmd) => cmd.run(repo, environment),
+            EnvCommand::Migrate(cmd) => cmd.run(repo, environment),
+        }
+    }
+}
+
+// dev keys
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// List the keys in [keys], and the environments they're used for.
+    List(KeysListCommand),
+    /// Add a teammate's SSH public key to [keys] and .dev/developers.
+    Add(KeysAddCommand),
+    /// Remove a key from [keys] and .dev/developers.
+    Remove(KeysRemoveCommand),
+    /// Re-encrypt environments for their current [keys].
+    Rekey(KeysRekeyCommand),
+    /// Compare each environment's [keys] with who it's really encrypted for.
+    Audit(KeysAuditCommand),
+}
+
+impl Runnable for &KeysCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        match self {
+            KeysCommand::List(cmd) => cmd.run(repo, environment),
+            KeysCommand::Add(cmd) => cmd.run(repo, environment),
+            KeysCommand::Remove(cmd) => cmd.run(repo, environment),
+            KeysCommand::Rekey(cmd) => cmd.run(repo, environment),
+            KeysCommand::Audit(cmd) => cmd.run(repo, environment),
+        }
+    }
+}
+
+// dev keys list
+#[derive(Args)]
+struct KeysListCommand;
+
+impl Runnable for &KeysListCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let developers = keys::read_developers(repo)?;
+        KeysListCommand::write_list(repo, developers.as_deref(), &mut std::io::stdout())
+            .map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+        Ok(0)
+    }
+}
+
+impl KeysListCommand {
+    fn write_list<W: Write>(repo: &Repo, developers: Option<&[String]>, out: &mut W) -> std::io::Result<()> {
+        // Each key, with the environments listing it, in the order they're
+        // first seen.
+        let mut listed: Vec<(&String, Vec<&str>)> = Vec::new();
+        let config = repo.config();
+        let groups: Vec<(String, &Vec<String>)> = config.key_groups.iter().flatten()
+            .map(|(name, keys)| (format!("@{}", name), keys))
+            .collect();
+        let config_keys = config.keys.iter().flatten()
+            .map(|(name, keys)| (name.as_str(), keys))
+            .chain(groups.iter().map(|(name, keys)| (name.as_str(), *keys)))
+            .flat_map(|(name, keys)| keys.iter().map(move |key| (Some(name), key)));
+        let all_keys = config_keys
+            .chain(developers.unwrap_or_default().iter().map(|key| (None, key)))
+            // References to groups aren't keys themselves.
+            .filter(|(_, key)| !key.starts_with('@'));
+        for (name, key) in all_keys {
+            let index = match listed.iter().position(|(listed, _)| keys::same_key(listed, key)) {
+                Some(index) => index,
+                None => {
+                    listed.push((key, Vec::new()));
+                    listed.len() - 1
+                },
+            };
+            listed[index].1.extend(name);
+        }
+
+        let labels: Vec<String> = listed.iter().map(|(key, _)| key_label(key)).collect();
+        let width = labels.iter().map(String::len).max().unwrap_or(0);
+        for (label, (key, names)) in labels.iter().zip(&listed) {
+            let names = match names.is_empty() {
+                true => "-".to_string(),
+                false => names.join(", "),
+            };
+            let former = developers.is_some_and(|developers| {
+                !developers.iter().any(|developer| keys::same_key(developer, key))
+            });
+            let note = if former { format!("  (not in {})", keys::DEVELOPERS_FILE) } else { String::new() };
+            writeln!(out, "{:width$}  {}{}", label, names, note, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+// dev keys add <key> [--env names]
+#[derive(Args)]
+struct KeysAddCommand {
+    /// An SSH public key, a file containing one, or `@<file>` for a file of
+    /// several keys, such as one saved from https://github.com/<user>.keys.
+    key: String,
+    /// The environments to add the key to, separated by commas, instead of
+    /// `default`.
+    #[arg(long = "env", value_delimiter = ',')]
+    environments: Vec<String>,
+}
+
+impl Runnable for &KeysAddCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let new_keys = KeysAddCommand::read_keys(&self.key)?;
+        for key in &new_keys {
+            keys::parse_recipient(key).map_err(|cause| AppError::InvalidKey(key.clone(), cause))?;
+        }
+
+        let names = match self.environments.is_empty() {
+            true => vec!["default".to_string()],
+            false => self.environments.clone(),
+        };
+        // A mistyped name would otherwise add a list to [keys] that nothing
+        // uses.
+        let existing = repo.environment_names().map_err(AgeDecryptError::Io)?;
+        for name in &names {
+            let config = repo.config();
+            let configured = name == "default"
+                || config.keys.as_ref().is_some_and(|keys| keys.contains_key(name))
+                || config.environments.as_ref().is_some_and(|environments| environments.contains_key(name));
+            if !configured && !existing.contains(name) {
+                return Err(AgeDecryptError::UnknownEnvironment(name.clone()).into());
+            }
+        }
+        let mut config = ConfigKeys::open(repo)?;
+        for name in &names {
+            let current = repo.get_environment(name.clone()).key_entries().to_vec();
+            for key in &new_keys {
+                config.add(name, key, &current);
+            }
+        }
+        config.save()?;
+        // A new developers file starts with everyone already in [keys], so
+        // they aren't reported as former developers.
+        if keys::read_developers(repo)?.is_none() {
+            let config = repo.config();
+            let existing: Vec<String> = config.keys.iter().chain(&config.key_groups)
+                .flat_map(|keys| keys.values().flatten().cloned())
+                .filter(|key| !key.starts_with('@'))
+                .collect();
+            keys::add_developers(repo, &existing)?;
+        }
+        keys::add_developers(repo, &new_keys)?;
+
+        let labels: Vec<String> = new_keys.iter().map(|key| key_label(key)).collect();
+        eprintln!("Added {} to [keys] for {}.", labels.join(", "), names.join(", "));
+        rekey_changed(repo)?;
+        Ok(0)
+    }
+}
+
+impl KeysAddCommand {
+    /// The keys given on the command line. Keys in an `@<file>` without a
+    /// comment are labelled with the file's name, like GitHub's `.keys` files.
+    fn read_keys(source: &str) -> Result<Vec<String>> {
+        let (path, label) = match source.strip_prefix('@') {
+            Some(path) => {
+                let label = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+                (Path::new(path), label)
+            },
+            None if Path::new(source).is_file() => (Path::new(source), None),
+            None => return Ok(vec![source.trim().into()]),
+        };
+
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::IoError(path.into(), e))?;
+        let keys = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|key| match &label {
+                Some(label) if key.split_whitespace().count() == 2 => format!("{} {}", key, label),
+                _ => key.into(),
+            })
+            .collect();
+        Ok(keys)
+    }
+}
+
+// dev keys remove <label>
+#[derive(Args)]
+struct KeysRemoveCommand {
+    /// The key's label from `dev keys list`, like `alice@laptop`, or the
+    /// whole key.
+    label: String,
+}
+
+impl Runnable for &KeysRemoveCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let developers = keys::read_developers(repo)?.unwrap_or_default();
+        let is_developer = developers.iter().any(|key| keys::key_matches(key, &self.label));
+
+        let mut config = ConfigKeys::open(repo)?;
+        let names = config.remove(&self.label);
+        if names.is_empty() && !is_developer {
+            return Err(AppError::UnknownKey(self.label.clone()));
+        }
+        config.save()?;
+        keys::remove_developers(repo, &self.label)?;
+
+        match names.is_empty() {
+            true => eprintln!("Removed {} from {}.", self.label, keys::DEVELOPERS_FILE),
+            false => eprintln!("Removed {} from the keys for {}.", self.label, names.join(", ")),
+        }
+        rekey_changed(repo)?;
+        Ok(0)
+    }
+}
+
+// dev keys rekey [names]
+#[derive(Args)]
+struct KeysRekeyCommand {
+    /// The environments to re-encrypt, instead of all of them.
+    names: Vec<String>,
+}
+
+impl Runnable for &KeysRekeyCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let names = match self.names.is_empty() {
+            true => repo.environment_names().map_err(AgeDecryptError::Io)?,
+            false => self.names.clone(),
+        };
+        rekey_environments(repo, &names)?;
+        Ok(0)
+    }
+}
+
+/// After changing the config's keys, offer to re-encrypt the environments
+/// whose keys changed, so the change takes effect.
+fn rekey_changed(repo: &Repo) -> Result<()> {
+    let mut updated = Repo::open(Some(repo.path().into()))?;
+    updated.set_assume_yes(repo.assume_yes());
+
+    let names = keys::changed_environments(repo, &updated).map_err(AgeDecryptError::Io)?;
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!("Re-encrypt {} for the new keys?", names.join(", "));
+    let confirmed = match updated.assume_yes() {
+        true => true,
+        false => match Confirm::new(&message).with_default(true).prompt() {
+            Ok(confirmed) => confirmed,
+            // The keys are already saved, so without a terminal to ask on,
+            // re-encrypting is left for later rather than failing.
+            Err(InquireError::NotTTY | InquireError::OperationCanceled | InquireError::OperationInterrupted) => false,
+            Err(err) => return Err(err.into()),
+        },
+    };
+    if !confirmed {
+        eprintln!("Run `dev keys rekey` to re-encrypt them later.");
+        return Ok(());
+    }
+    rekey_environments(&updated, &names)
+}
+
+/// Re-encrypt environments and their sections, skipping any that can't be
+/// decrypted.
+fn rekey_environments(repo: &Repo, names: &[String]) -> Result<()> {
+    for name in names {
+        let environment = repo.get_environment(name.clone());
+        let sections = environment.sections().into_iter().filter(Environment::exists);
+        for environment in std::iter::once(environment).chain(sections) {
+            let name = environment.display_name();
+            match environment.rekey() {
+                Ok(()) => eprintln!("Re-encrypted '{}'.", name),
+                Err(AppError::AgeDecryptError(err)) => eprintln!("Skipping '{}', which can't be decrypted: {}", name, err),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+// dev keys audit
+#[derive(Args)]
+struct KeysAuditCommand;
+
+impl Runnable for &KeysAuditCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let developers = keys::read_developers(repo)?;
+        let mut failed = Vec::new();
+        for name in repo.environment_names().map_err(AgeDecryptError::Io)? {
+            let environment = repo.get_environment(name);
+            let sections = environment.sections().into_iter().filter(Environment::exists);
+            for environment in std::iter::once(environment).chain(sections) {
+                let name = environment.display_name();
+                let audit = Audit::new(&environment, developers.as_deref())?;
+                KeysAuditCommand::write_audit(&name, &audit, &mut std::io::stdout())
+                    .map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+                if !audit.is_ok() {
+                    failed.push(name);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(AppError::KeyAuditFailed(failed));
+        }
+        Ok(0)
+    }
+}
+
+impl KeysAuditCommand {
+    fn write_audit<W: Write>(name: &str, audit: &Audit, out: &mut W) -> std::io::Result<()> {
+        if audit.is_ok() {
+            return writeln!(out, "{}: ok", name);
+        }
+        writeln!(out, "{}:", name)?;
+        for label in &audit.missing {
+            writeln!(out, "  {} is in [keys] but can't decrypt, the environment needs re-encrypting", label)?;
+        }
+        for label in &audit.unexpected {
+            writeln!(out, "  {} can decrypt but isn't in [keys]", label)?;
+        }
+        for label in &audit.former {
+            writeln!(out, "  {} can decrypt but isn't in {}", label, keys::DEVELOPERS_FILE)?;
+        }
+        Ok(())
+    }
+}
+
+// dev env list
+#[derive(Args)]
+struct EnvListCommand;
+
+impl Runnable for &EnvListCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let names = repo.environment_names()
+            .map_err(AgeDecryptError::Io)?;
+        let mut known = keys::read_developers(repo)?.unwrap_or_default();
+        known.extend(keys::config_keys(repo.config()));
+        EnvListCommand::write_list(repo, &names, &known, &mut std::io::stdout())
+            .map_err(|e| AppError::IoError("<stdout>".into(), e))?;
+        Ok(0)
+    }
+}
+
+impl EnvListCommand {
+    /// Write each environment, whether it can be decrypted, and the keys it's
+    /// encrypted for, read from its file and labelled with the `known` keys.
+    fn write_list<W: Write>(repo: &Repo, names: &[String], known: &[String], out: &mut W) -> std::io::Result<()> {
+        let width = names.iter()
+            .map(String::len)
+            .max()
+            .unwrap_or(0);
+        for name in names {
+            let environment = repo.get_environment(name.clone());
+            let access = match environment.decrypt() {
+                Ok(_) => "decryptable",
+                Err(_) => "no access",
+            };
+            // The file's header shows who can really decrypt it, even if
+            // [keys] has changed since it was encrypted.
+            let recipients = match keys::read_recipients(&environment.path()) {
+                Ok(recipients) => recipients.iter()
+                    .map(|recipient| keys::recipient_label(recipient, known))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Err(err) => err.to_string(),
+            };
+            writeln!(out, "{:width$}  {:11}  {}", name, access, recipients, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+// dev env create <name>
+#[derive(Args)]
+struct EnvCreateCommand {
+    /// The name of the environment to create.
+    name: String,
+}
+
+impl Runnable for &EnvCreateCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        repo.get_environment(self.name.clone()).create()?;
+        eprintln!("Created environment '{}'.", self.name);
+        Ok(0)
+    }
+}
+
+// dev env copy <source> <destination>
+#[derive(Args)]
+struct EnvCopyCommand {
+    /// The name of the environment to copy.
+    source: String,
+    /// The name of the new environment.
+    destination: String,
+}
+
+impl Runnable for &EnvCopyCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let source = repo.get_environment(self.source.clone());
+        let destination = repo.get_environment(self.destination.clone());
+        source.confirm()?;
+        destination.confirm()?;
+        source.copy_to(&destination)?;
+        eprintln!("Copied environment '{}' to '{}'.", self.source, self.destination);
+        Ok(0)
+    }
+}
+
+// dev env rename <source> <destination>
+#[derive(Args)]
+struct EnvRenameCommand {
+    /// The current name of the environment.
+    source: String,
+    /// The new name of the environment.
+    destination: String,
+}
+
+impl Runnable for &EnvRenameCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let source = repo.get_environment(self.source.clone());
+        let destination = repo.get_environment(self.destination.clone());
+        source.confirm()?;
+        destination.confirm()?;
+        // The destination may have different keys configured, so the values
+        // need to be re-encrypted rather than just moving the file.
+        source.copy_to(&destination)?;
+        source.delete()?;
+        eprintln!("Renamed environment '{}' to '{}'.", self.source, self.destination);
+        Ok(0)
+    }
+}
+
+// dev env delete <name>
+#[derive(Args)]
+struct EnvDeleteCommand {
+    /// The name of the environment to delete.
+    name: String,
+}
+
+impl Runnable for &EnvDeleteCommand {
+    fn run(self, repo: &Repo, _environment: &Environment<'_>) -> Result<i32> {
+        let environment = repo.get_environment(self.name.clone());
+        if !environment.exists() {
+            return Err(AgeDecryptError::UnknownEnvironment(self.name.clone()).into());
+        }
+
+        if environment.config().protected {
+            environment.confirm()?;
+        } else if !repo.assume_yes() {
+            let confirmed = Confirm::new(&format!("Delete the '{}' environment and all of its variables?", self.name))
+                .with_default(false)
+                .prompt()
+                .unwrap_or(false);
+            if !confirmed {
+                eprintln!("Delete cancelled.");
+                return Ok(0);
+            }
+        }
+
+        environment.delete()?;
+        eprintln!("Deleted environment '{}'.", self.name);
+        Ok(0)
+    }
+}
+
+// dev env migrate --from <sops|dotenvx> <file> [name]
+#[derive(Args)]
+struct EnvMigrateCommand {
+    /// The tool the file was encrypted with.
+    #[arg(long, value_enum)]
+    from: MigrateSource,
+    /// The encrypted file.
+    file: PathBuf,
+    /// The environment to create, which defaults to the one selected with -e.
+    name: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum MigrateSource {
+    /// A YAML, JSON or dotenv file encrypted by SOPS for SSH keys.
+    Sops,
+    /// A `.env` file encrypted by dotenvx, with its private key in
+    /// `DOTENV_PRIVATE_KEY*` or `.env.keys`.
+    Dotenvx,
+}
+
+impl Runnable for &EnvMigrateCommand {
+    fn run(self, repo: &Repo, environment: &Environment<'_>) -> Result<i32> {
+        let name = self.name.clone().unwrap_or_else(|| environment.name().into());
+        let destination = repo.get_environment(name.clone());
+        destination.check_name()?;
+        if destination.exists() {
+            return Err(AppError::EnvironmentExists(name));
+        }
+        destination.confirm()?;
+
+        let values = match self.from {
+            MigrateSource::Sops => sops::read(repo, &self.file)?,
+            MigrateSource::Dotenvx => {
+                let content = std::fs::read_to_string(&self.file)
+                    .map_err(|e| AppError::IoError(self.file.clone(), e))?;
+                let values = ConfigImportCommand::parse_dotenv(&content)
+                    .map_err(|e| AppError::ImportError(self.file.clone(), e))?;
+                dotenvx::decrypt(&self.file, values)?
+            },
+        };
+        destination.set_values(&values)?;
+        eprintln!("Migrated {} variable(s) from {:?} into the '{}' environment.", values.len(), self.file, name);
+        if destination.config().sops.is_some() {
+            eprintln!("It's no longer read from SOPS, so `sops` can be removed from [environments.{}].", name);
+        }
+        Ok(0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::TestSetup;
+    use std::env;
+    use tempfile::NamedTempFile;
+    use crate::test_setup::{TestSetup, OTHER_KEY, PUBLIC_KEY};
 
     fn set_envs(setup: &mut TestSetup) {
         let env = setup.env();
-        let mut file = env.decrypt().unwrap();
-        writeln!(file, "ABC=123").unwrap();
-        writeln!(file, "{}", "TEST = { b = 2, a = 1 }").unwrap();
-        file.flush().unwrap();
-        env.encrypt(&file).unwrap();
+        let mut content = Vec::new();
+        writeln!(content, "ABC=123").unwrap();
+        writeln!(content, "TEST = {{ b = 2, a = 1 }}").unwrap();
+        env.encrypt(&content).unwrap();
     }
 
     #[test]
@@ -425,18 +1853,29 @@ mod tests {
         set_envs(&mut setup);
         let mut output = Vec::new();
 
-        ConfigExportCommand::format_raw(&setup.env(), &mut output).unwrap();
+        ConfigExportCommand::format_raw(&setup.env().decrypt().unwrap(), &mut output).unwrap();
 
         assert_eq!(&output, b"ABC=123\nTEST = { b = 2, a = 1 }\n");
     }
 
+    #[test]
+    fn test_config_export_write_error() {
+        let mut setup = TestSetup::new();
+        set_envs(&mut setup);
+        let mut full: &mut [u8] = &mut [];
+
+        let result = ConfigExportCommand::format_docker(&setup.env().string_values().unwrap(), &mut full);
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+    }
+
     #[test]
     fn test_config_export_json_format() {
         let mut setup = TestSetup::new();
         set_envs(&mut setup);
         let mut output = Vec::new();
 
-        ConfigExportCommand::format_json(&setup.env(), &mut output).unwrap();
+        ConfigExportCommand::format_json(&setup.env().values().unwrap(), &mut output).unwrap();
 
         assert_eq!(&output, br#"{
   "ABC": 123,
@@ -453,8 +1892,452 @@ mod tests {
         set_envs(&mut setup);
         let mut output = Vec::new();
 
-        ConfigExportCommand::format_docker(&setup.env(), &mut output).unwrap();
+        ConfigExportCommand::format_docker(&setup.env().string_values().unwrap(), &mut output).unwrap();
 
         assert_eq!(&output, b"ABC=123\nTEST={\"a\":1,\"b\":2}\n");
     }
+
+    fn set_quoted_envs(setup: &mut TestSetup) {
+        let env = setup.env();
+        let mut content = Vec::new();
+        writeln!(content, r#"QUOTED = 'say "hi" to $USER\n'"#).unwrap();
+        writeln!(content, "MULTILINE = \"\"\"\nfirst\nsecond 100%\"\"\"").unwrap();
+        writeln!(content, "WHEN = 1979-05-27T07:32:00Z").unwrap();
+        env.encrypt(&content).unwrap();
+    }
+
+    #[test]
+    fn test_config_export_systemd_format() {
+        let mut setup = TestSetup::new();
+        set_envs(&mut setup);
+        let mut output = Vec::new();
+
+        ConfigExportCommand::format_systemd(&setup.env().string_values().unwrap(), &mut output).unwrap();
+
+        assert_eq!(&output, b"ABC=\"123\"\nTEST=\"{\\\"a\\\":1,\\\"b\\\":2}\"\n");
+    }
+
+    #[test]
+    fn test_config_export_systemd_format_escaping() {
+        let mut setup = TestSetup::new();
+        set_quoted_envs(&mut setup);
+        let mut output = Vec::new();
+
+        ConfigExportCommand::format_systemd(&setup.env().string_values().unwrap(), &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "MULTILINE=\"first\nsecond 100%\"\n",
+            "QUOTED=\"say \\\"hi\\\" to \\$USER\\\\n\"\n",
+            "WHEN=\"1979-05-27T07:32:00Z\"\n",
+        ));
+    }
+
+    #[test]
+    fn test_config_export_github_format() {
+        let mut setup = TestSetup::new();
+        set_quoted_envs(&mut setup);
+        let mut output = Vec::new();
+        let mut mask = Vec::new();
+
+        ConfigExportCommand::format_github(&setup.env().string_values().unwrap(), &mut output, &mut mask).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "MULTILINE<<EOF_MULTILINE\nfirst\nsecond 100%\nEOF_MULTILINE\n",
+            "QUOTED<<EOF_QUOTED\nsay \"hi\" to $USER\\n\nEOF_QUOTED\n",
+            "WHEN<<EOF_WHEN\n1979-05-27T07:32:00Z\nEOF_WHEN\n",
+        ));
+        assert_eq!(String::from_utf8(mask).unwrap(), concat!(
+            "::add-mask::first\n",
+            "::add-mask::second 100%25\n",
+            "::add-mask::say \"hi\" to $USER\\n\n",
+            "::add-mask::1979-05-27T07:32:00Z\n",
+        ));
+    }
+
+    #[test]
+    fn test_config_export_github_format_delimiter_collision() {
+        let setup = TestSetup::new();
+        let env = setup.env();
+        let mut content = Vec::new();
+        writeln!(content, "KEY = \"\"\"\nEOF_KEY\nvalue\"\"\"").unwrap();
+        env.encrypt(&content).unwrap();
+        let mut output = Vec::new();
+
+        ConfigExportCommand::format_github(&env.string_values().unwrap(), &mut output, &mut std::io::sink()).unwrap();
+
+        assert_eq!(&output, b"KEY<<EOF_KEY_\nEOF_KEY\nvalue\nEOF_KEY_\n");
+    }
+
+    #[test]
+    fn test_config_export_terraform_format() {
+        let mut setup = TestSetup::new();
+        set_quoted_envs(&mut setup);
+        let mut output = Vec::new();
+
+        ConfigExportCommand::format_terraform(&setup.env().values().unwrap(), &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), r#"{
+  "MULTILINE": "first\nsecond 100%",
+  "QUOTED": "say \"hi\" to $USER\\n",
+  "WHEN": "1979-05-27T07:32:00Z"
+}
+"#)
+    }
+
+    #[test]
+    fn test_config_import_dotenv() {
+        let content = concat!(
+            "# A comment\n",
+            "\n",
+            "PLAIN=value # trailing comment\n",
+            "export EXPORTED = spaced\n",
+            "SINGLE='literal $HOME \\n'\n",
+            "DOUBLE=\"say \\\"hi\\\"\\nbye\"\n",
+            "MULTILINE=\"first\n",
+            "second\"\n",
+            "EMPTY=\n",
+        );
+
+        let values = ConfigImportCommand::parse_dotenv(content).unwrap();
+
+        assert_eq!(values, BTreeMap::from([
+            ("PLAIN".into(), Value::String("value".into())),
+            ("EXPORTED".into(), Value::String("spaced".into())),
+            ("SINGLE".into(), Value::String("literal $HOME \\n".into())),
+            ("DOUBLE".into(), Value::String("say \"hi\"\nbye".into())),
+            ("MULTILINE".into(), Value::String("first\nsecond".into())),
+            ("EMPTY".into(), Value::String("".into())),
+        ]));
+    }
+
+    #[test]
+    fn test_config_import_dotenv_errors() {
+        let result = ConfigImportCommand::parse_dotenv("A=1\nNOT A VARIABLE\n");
+        assert_eq!(result.unwrap_err(), "line 2: expected KEY=value");
+
+        let result = ConfigImportCommand::parse_dotenv("A=\"unterminated\n");
+        assert_eq!(result.unwrap_err(), "line 1: missing closing quote");
+    }
+
+    #[test]
+    fn test_config_import_docker() {
+        env::set_var("DEV_TEST_IMPORT_PASSTHROUGH", "from host");
+        let content = concat!(
+            "# A comment\n",
+            "QUOTED=\"kept\" # as is\n",
+            "DEV_TEST_IMPORT_PASSTHROUGH\n",
+            "DEV_TEST_IMPORT_UNSET\n",
+        );
+
+        let values = ConfigImportCommand::parse_docker(content).unwrap();
+
+        assert_eq!(values, BTreeMap::from([
+            ("QUOTED".into(), Value::String("\"kept\" # as is".into())),
+            ("DEV_TEST_IMPORT_PASSTHROUGH".into(), Value::String("from host".into())),
+        ]));
+    }
+
+    #[test]
+    fn test_config_import_json() {
+        let content = r#"{"ABC": 123, "NAME": "dev", "UNSET": null, "TEST": {"a": 1}}"#;
+
+        let values = ConfigImportCommand::parse_json(content).unwrap();
+
+        assert_eq!(toml::to_string(&values).unwrap(), "ABC = 123\nNAME = \"dev\"\n\n[TEST]\na = 1\n");
+    }
+
+    #[test]
+    fn test_config_import_diff() {
+        let old = BTreeMap::from([
+            ("KEPT".into(), Value::Integer(1)),
+            ("CHANGED".into(), Value::Integer(1)),
+            ("REMOVED".into(), Value::Integer(1)),
+        ]);
+        let new = BTreeMap::from([
+            ("KEPT".into(), Value::Integer(1)),
+            ("CHANGED".into(), Value::Integer(2)),
+            ("ADDED".into(), Value::Integer(1)),
+        ]);
+        let mut output = Vec::new();
+
+        let changes = ConfigImportCommand::write_diff(&old, &new, &mut output).unwrap();
+
+        assert_eq!(changes, 3);
+        assert_eq!(&output, b"+ ADDED\n~ CHANGED\n- REMOVED\n");
+    }
+
+    #[test]
+    fn test_config_import_assume_yes() {
+        let mut setup = TestSetup::new();
+        setup.repo.set_assume_yes(true);
+        setup.env().encrypt(b"REGION = \"eu-west-1\"\n").unwrap();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "SECRET=value").unwrap();
+        let command = ConfigImportCommand { file: file.path().into(), format: None, replace: false, shred: false };
+
+        command.run(&setup.repo, &setup.env()).unwrap();
+
+        let values = setup.env().values().unwrap();
+        assert_eq!(values["REGION"], Value::String("eu-west-1".into()));
+        assert_eq!(values["SECRET"], Value::String("value".into()));
+    }
+
+    #[test]
+    fn test_config_import_shred() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "SECRET=value").unwrap();
+        let path = file.into_temp_path().keep().unwrap();
+
+        ConfigImportCommand::shred(&path).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_env_list() {
+        let mut setup = TestSetup::new();
+        set_envs(&mut setup);
+        // An environment encrypted for someone else's key can't be decrypted.
+        let path = setup.repo.path();
+        let config = Config {
+            keys: Some([("other".into(), vec![OTHER_KEY.into()])].into()),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(path.into(), path.to_str().unwrap().into(), config);
+        repo.get_environment("other".into()).create().unwrap();
+        std::fs::write(path.join(".dev/env.age.broken"), "not an age file").unwrap();
+        let mut output = Vec::new();
+
+        // Recipients are read from the files, so they're listed even though
+        // the setup's config has different keys.
+        let names = setup.repo.environment_names().unwrap();
+        let known = vec![PUBLIC_KEY.trim().to_string()];
+        EnvListCommand::write_list(&setup.repo, &names, &known, &mut output).unwrap();
+
+        let tag = keys::key_tag(OTHER_KEY).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!(concat!(
+            "broken  no access    The environment file is invalid, because it isn't valid TOML\n",
+            "local   decryptable  testkey\n",
+            "other   no access    unknown ssh-ed25519 key {}\n",
+        ), tag));
+    }
+
+    #[test]
+    fn test_run_returns_exit_code() {
+        let setup = TestSetup::new();
+        setup.env().encrypt(b"REGION = \"eu-west-1\"\n").unwrap();
+        let repo = setup.repo.path().to_str().unwrap();
+
+        // Usage errors are returned rather than exiting, so the Python entry
+        // point can raise them as SystemExit.
+        assert_eq!(run(["dev", "--repo", repo, "--no-such-flag"]), 2);
+        assert_eq!(run(["dev", "--repo", repo, "--version"]), 0);
+
+        // So are the exit codes of commands that are run.
+        let command = SubCommand::Run(RunCommand { command: "sh".into(), args: vec!["-c".into(), "exit 3".into()] });
+        assert_eq!((&command).run(&setup.repo, &setup.env()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_start_keeps_exit_code() {
+        let setup = TestSetup::new();
+        setup.env().encrypt(b"").unwrap();
+        let path = setup.repo.path();
+        let config = Config {
+            commands: Some(Commands { start: Some("exit 3".into()), ..Commands::default() }),
+            hooks: Some(Hooks { post_start: Some("exit 1".into()), ..Hooks::default() }),
+            keys: setup.repo.config().keys.clone(),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(path.into(), path.to_str().unwrap().into(), config);
+
+        let code = StartCommand.run(&repo, &repo.get_environment("local".into())).unwrap();
+
+        assert_eq!(code, 3);
+    }
+
+    #[test]
+    fn test_scan_skipped_environments() {
+        let mut setup = TestSetup::new();
+        let other = setup.repo.path().join(".dev/env.age.other");
+        std::fs::write(other, "not an age file").unwrap();
+        let scan = |setup: &TestSetup, strict| ScanCommand { paths: vec![], strict }.run(&setup.repo, &setup.env());
+
+        assert!(matches!(scan(&setup, false), Err(AppError::NoEnvironmentsScanned)));
+
+        set_envs(&mut setup);
+        let Err(AppError::EnvironmentsSkipped(names)) = scan(&setup, true) else { panic!("Expected skipped environments") };
+        assert_eq!(names, vec!["other"]);
+    }
+
+    #[test]
+    fn test_db_command_parsing() {
+        let cli = Cli::try_parse_from(["dev", "db", "-c", "select 1"]).unwrap();
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        assert!(cmd.command.is_none());
+        assert_eq!(cmd.args, vec!["-c", "select 1"]);
+
+        let cli = Cli::try_parse_from(["dev", "psql", "--read-only"]).unwrap();
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        assert!(cmd.read_only);
+
+        let cli = Cli::try_parse_from(["dev", "psql", "-p", "5433"]).unwrap();
+        assert!(cli.package.is_none());
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        assert_eq!(cmd.args, vec!["-p", "5433"]);
+
+        let cli = Cli::try_parse_from(["dev", "psql", "-v", "ON_ERROR_STOP=1"]).unwrap();
+        assert!(!cli.verbose);
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        assert_eq!(cmd.args, vec!["-v", "ON_ERROR_STOP=1"]);
+
+        let cli = Cli::try_parse_from(["dev", "db", "dump", "-e", "stg", "-o", "stg.dump"]).unwrap();
+        assert_eq!(cli.environment.as_deref(), Some("stg"));
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        let Some(DbSubCommand::Dump(dump)) = cmd.command else { panic!("Expected dump command") };
+        assert_eq!(dump.output, Some(PathBuf::from("stg.dump")));
+
+        let cli = Cli::try_parse_from(["dev", "db", "copy", "--from", "dev", "--to", "local"]).unwrap();
+        let SubCommand::Db(cmd) = cli.command else { panic!("Expected db command") };
+        let Some(DbSubCommand::Copy(copy)) = cmd.command else { panic!("Expected copy command") };
+        assert_eq!((copy.from.as_str(), copy.to.as_str()), ("dev", "local"));
+    }
+
+    #[test]
+    fn test_task_command_parsing() {
+        let cli = Cli::try_parse_from(["dev", "migrate", "--fake", "0001"]).unwrap();
+        let SubCommand::External(args) = cli.command else { panic!("Expected task") };
+        assert_eq!(args, vec!["migrate", "--fake", "0001"]);
+
+        let cli = Cli::try_parse_from(["dev", "task", "migrate", "--fake"]).unwrap();
+        let SubCommand::Task(cmd) = cli.command else { panic!("Expected task command") };
+        assert_eq!(cmd.name.as_deref(), Some("migrate"));
+        assert_eq!(cmd.args, vec!["--fake"]);
+
+        assert!(Cli::try_parse_from(["dev", "task"]).is_err());
+    }
+
+    #[test]
+    fn test_scan_repo_args() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+
+        assert_eq!(scan_repo_args(&args(&["dev", "--help"])), (None, None));
+        assert_eq!(
+            scan_repo_args(&args(&["dev", "--repo", "../other", "--package=api", "--help"])),
+            (Some(PathBuf::from("../other")), Some("api".into())),
+        );
+        assert_eq!(
+            scan_repo_args(&args(&["dev", "--repo=../other", "run", "--", "--package", "api"])),
+            (Some(PathBuf::from("../other")), None),
+        );
+    }
+
+    #[test]
+    fn test_task_list() {
+        let config: Config = toml::from_str("
+            [commands.tasks]
+            migrate = { command = 'alembic upgrade head', description = 'Run migrations' }
+            seed = 'python seed.py'
+        ").unwrap();
+        let repo = Repo::from_parts("/repo".into(), "/home".into(), config);
+        let cmd = TaskCommand { list: true, name: None, args: Vec::new() };
+        let mut output = Vec::new();
+
+        cmd.write_list(&repo, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "migrate  Run migrations\n",
+            "seed     python seed.py\n",
+        ));
+        assert_eq!(tasks_help(&repo).unwrap(), concat!(
+            "Tasks:\n",
+            "  migrate  Run migrations\n",
+            "  seed     python seed.py",
+        ));
+        assert!(shadowed_tasks(&repo).is_empty());
+    }
+
+    #[test]
+    fn test_shadowed_tasks() {
+        let config: Config = toml::from_str("
+            [commands.tasks]
+            check = 'cargo test'
+            lint = 'cargo clippy'
+            psql = 'psql'
+        ").unwrap();
+        let repo = Repo::from_parts("/repo".into(), "/home".into(), config);
+
+        assert_eq!(shadowed_tasks(&repo), vec!["check", "psql"]);
+    }
+
+    #[test]
+    fn test_keys_audit_output() {
+        let audit = Audit {
+            missing: vec!["alice@laptop".into()],
+            unexpected: vec!["bob@old".into()],
+            former: vec!["bob@old".into()],
+        };
+        let mut output = Vec::new();
+
+        KeysAuditCommand::write_audit("local", &Audit::default(), &mut output).unwrap();
+        KeysAuditCommand::write_audit("prd", &audit, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "local: ok\n",
+            "prd:\n",
+            "  alice@laptop is in [keys] but can't decrypt, the environment needs re-encrypting\n",
+            "  bob@old can decrypt but isn't in [keys]\n",
+            "  bob@old can decrypt but isn't in .dev/developers\n",
+        ));
+    }
+
+    #[test]
+    fn test_keys_list() {
+        let config: Config = toml::from_str(&format!("
+            [keys]
+            default = ['{key}']
+            prd = ['{key}', '@backend']
+
+            [key_groups]
+            backend = ['{other}']
+        ", key = PUBLIC_KEY.trim(), other = OTHER_KEY)).unwrap();
+        let repo = Repo::from_parts("/repo".into(), "/home".into(), config);
+        let developers = vec![PUBLIC_KEY.trim().to_string()];
+        let mut output = Vec::new();
+
+        KeysListCommand::write_list(&repo, Some(&developers), &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "testkey       default, prd\n",
+            "alice@laptop  @backend  (not in .dev/developers)\n",
+        ));
+    }
+
+    #[test]
+    fn test_keys_add_read_keys() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("alice.keys");
+        std::fs::write(&path, "ssh-ed25519 AAAA1\nssh-ed25519 AAAA2 laptop\n").unwrap();
+
+        let keys = KeysAddCommand::read_keys(&format!("@{}", path.display())).unwrap();
+        assert_eq!(keys, vec!["ssh-ed25519 AAAA1 alice", "ssh-ed25519 AAAA2 laptop"]);
+
+        let keys = KeysAddCommand::read_keys(path.to_str().unwrap()).unwrap();
+        assert_eq!(keys, vec!["ssh-ed25519 AAAA1", "ssh-ed25519 AAAA2 laptop"]);
+
+        let keys = KeysAddCommand::read_keys("ssh-ed25519 AAAA3 bob ").unwrap();
+        assert_eq!(keys, vec!["ssh-ed25519 AAAA3 bob"]);
+    }
+
+    #[test]
+    fn test_keys_add_unknown_environment() {
+        let setup = TestSetup::new();
+        setup.env().encrypt(b"").unwrap();
+        let command = |name: &str| KeysAddCommand { key: OTHER_KEY.into(), environments: vec![name.into()] };
+
+        let result = command("prod").run(&setup.repo, &setup.env());
+
+        assert!(matches!(result, Err(AppError::AgeDecryptError(AgeDecryptError::UnknownEnvironment(name))) if name == "prod"));
+        assert!(!setup.repo.path().join(".dev/config.toml").exists());
+    }
 }
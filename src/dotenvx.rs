@@ -0,0 +1,223 @@
+//! Reading files encrypted by dotenvx, so projects that already use it can
+//! move to dev. dotenvx encrypts each value in a `.env` file separately, with
+//! ECIES for a secp256k1 key whose public half is kept in the file. The
+//! private key is read from `DOTENV_PRIVATE_KEY`, or the variable named after
+//! the file like `DOTENV_PRIVATE_KEY_PRODUCTION`, or from `.env.keys` next to
+//! the file.
+//!
+//! dotenvx doesn't authenticate the file as a whole, so anyone who can write
+//! to it can add values, encrypted or not.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use aes_gcm::aead::consts::U16;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hkdf::Hkdf;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{PublicKey, SecretKey};
+use sha2::Sha256;
+use toml::Value;
+use zeroize::Zeroizing;
+
+use crate::error::*;
+use crate::secrets::Secrets;
+
+/// The cipher values are encrypted with, which uses 16 byte nonces rather
+/// than the usual 12.
+type Cipher = AesGcm<Aes256, U16>;
+
+/// The prefix of encrypted values.
+const ENCRYPTED_PREFIX: &str = "encrypted:";
+
+/// The prefix of the variables holding a file's public key, which aren't
+/// part of the environment.
+const PUBLIC_KEY_PREFIX: &str = "DOTENV_PUBLIC_KEY";
+
+/// The file dotenvx keeps private keys in, next to the encrypted files.
+const KEYS_FILE: &str = ".env.keys";
+
+/// The length of an uncompressed secp256k1 public key.
+const PUBLIC_KEY_LENGTH: usize = 65;
+const NONCE_LENGTH: usize = 16;
+const TAG_LENGTH: usize = 16;
+
+/// Whether a file's variables were encrypted by dotenvx.
+fn is_encrypted(values: &BTreeMap<String, Value>) -> bool {
+    values.iter().any(|(name, value)| {
+        name.starts_with(PUBLIC_KEY_PREFIX)
+            || value.as_str().is_some_and(|value| value.starts_with(ENCRYPTED_PREFIX))
+    })
+}
+
+/// Decrypt the variables read from a file encrypted by dotenvx. Values that
+/// aren't encrypted are kept as they are.
+pub fn decrypt(path: &Path, values: BTreeMap<String, Value>) -> std::result::Result<Secrets<Value>, AgeDecryptError> {
+    if !is_encrypted(&values) {
+        return Err(invalid("it wasn't encrypted by dotenvx"));
+    }
+    let key_name = private_key_name(path);
+    let keys = private_keys(path, &key_name)?;
+
+    let mut decrypted = Secrets::new();
+    for (name, value) in values {
+        if name.starts_with(PUBLIC_KEY_PREFIX) {
+            continue;
+        }
+        let value = match value.as_str().and_then(|value| value.strip_prefix(ENCRYPTED_PREFIX)) {
+            Some(encrypted) => {
+                let plaintext = keys.iter()
+                    .find_map(|key| decrypt_value(key, encrypted))
+                    .ok_or_else(|| invalid(&format!("the value of {} can't be decrypted with {}", name, key_name)))?;
+                Value::String(plaintext.to_string())
+            },
+            None => value,
+        };
+        decrypted.insert(name, value);
+    }
+    Ok(decrypted)
+}
+
+/// The variable holding the private key for a file, which is named after the
+/// part of the file name after `.env`, like `DOTENV_PRIVATE_KEY_PRODUCTION`
+/// for `.env.production`.
+fn private_key_name(path: &Path) -> String {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let suffix: String = name.strip_prefix(".env").unwrap_or(&name)
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    match suffix.is_empty() {
+        true => "DOTENV_PRIVATE_KEY".into(),
+        false => format!("DOTENV_PRIVATE_KEY_{}", suffix),
+    }
+}
+
+/// The private keys for a file, from the environment, or from `.env.keys`.
+/// A variable can hold several keys separated by commas, while rotating them.
+fn private_keys(path: &Path, key_name: &str) -> std::result::Result<Vec<SecretKey>, AgeDecryptError> {
+    let value = match std::env::var(key_name) {
+        Ok(value) => Zeroizing::new(value),
+        Err(_) => {
+            let keys_path = path.parent().unwrap_or(Path::new("")).join(KEYS_FILE);
+            let content = match std::fs::read_to_string(&keys_path) {
+                Ok(content) => Zeroizing::new(content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Zeroizing::new(String::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let value = content.lines()
+                .filter_map(|line| line.trim_start().strip_prefix("export ").unwrap_or(line).split_once('='))
+                .find(|(name, _)| name.trim() == key_name)
+                .map(|(_, value)| value.trim().trim_matches(['"', '\'']).to_string())
+                .ok_or_else(|| invalid(&format!("its private key wasn't found in {} or {}", key_name, KEYS_FILE)))?;
+            Zeroizing::new(value)
+        },
+    };
+
+    value.split(',')
+        .map(|key| {
+            let bytes = Zeroizing::new(hex(key.trim()).ok_or_else(|| invalid(&format!("{} isn't a hex encoded private key", key_name)))?);
+            SecretKey::from_slice(&bytes).map_err(|_| invalid(&format!("{} isn't a valid private key", key_name)))
+        })
+        .collect()
+}
+
+/// Decrypt a base64 encoded value, made of the ephemeral public key, nonce,
+/// tag and ciphertext. The AES key is derived with HKDF from the ephemeral
+/// public key and the shared point, both uncompressed. Returns `None` if it
+/// wasn't encrypted for this key.
+fn decrypt_value(key: &SecretKey, encrypted: &str) -> Option<Zeroizing<String>> {
+    let data = STANDARD.decode(encrypted).ok()?;
+    if data.len() < PUBLIC_KEY_LENGTH + NONCE_LENGTH + TAG_LENGTH {
+        return None;
+    }
+    let (ephemeral, rest) = data.split_at(PUBLIC_KEY_LENGTH);
+    let (nonce, rest) = rest.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+
+    let ephemeral_key = PublicKey::from_sec1_bytes(ephemeral).ok()?;
+    let shared = (ephemeral_key.to_projective() * *key.to_nonzero_scalar()).to_affine();
+    let mut secret = Zeroizing::new(ephemeral.to_vec());
+    secret.extend_from_slice(shared.to_encoded_point(false).as_bytes());
+    let mut aes_key = Zeroizing::new([0; 32]);
+    Hkdf::<Sha256>::new(None, &secret).expand(&[], aes_key.as_mut()).ok()?;
+
+    let mut message = ciphertext.to_vec();
+    message.extend_from_slice(tag);
+    let cipher = Cipher::new_from_slice(aes_key.as_ref()).ok()?;
+    let plaintext = cipher.decrypt(Nonce::<U16>::from_slice(nonce), message.as_slice()).ok()?;
+    String::from_utf8(plaintext).ok().map(Zeroizing::new)
+}
+
+fn hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(value.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(reason: &str) -> AgeDecryptError {
+    AgeDecryptError::InvalidFile(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f";
+    const PUBLIC_KEY: &str = "02505f234a81fe3af88625ebda259dbaec44c72b181e2f64735f8b8ec8d7cf7377";
+    /// "tok_123", encrypted for the public key the way dotenvx does.
+    const ENCRYPTED: &str = "encrypted:BFvl6UeCCWdKluYPHwN/YXZUD9AB+h1kaUdwxWp3CcQsA1qIw4Q9v4sQ1SzHHibM4XHhqh4y+OGTyfamvY8HuL8AAQIDBAUGBwgJCgsMDQ4PYtr+WkzijnigF4w9EW1mgCO35nAwTeE=";
+
+    fn values() -> BTreeMap<String, Value> {
+        BTreeMap::from([
+            ("DOTENV_PUBLIC_KEY_CI".into(), Value::String(PUBLIC_KEY.into())),
+            ("API_TOKEN".into(), Value::String(ENCRYPTED.into())),
+            ("REGION".into(), Value::String("eu-west-1".into())),
+        ])
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".env.ci");
+        std::fs::write(dir.path().join(KEYS_FILE), format!("#/ private keys /\nDOTENV_PRIVATE_KEY_CI=\"{}\"\n", PRIVATE_KEY)).unwrap();
+
+        assert!(is_encrypted(&values()));
+        let decrypted = decrypt(&path, values()).unwrap();
+
+        assert_eq!(decrypted.keys().collect::<Vec<_>>(), vec!["API_TOKEN", "REGION"]);
+        assert_eq!(decrypted["API_TOKEN"], Value::String("tok_123".into()));
+        assert_eq!(decrypted["REGION"], Value::String("eu-west-1".into()));
+    }
+
+    #[test]
+    fn test_decrypt_errors() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".env.ci");
+        let error = |values| decrypt(&path, values).unwrap_err().to_string();
+
+        assert_eq!(error(values()), "The environment file is invalid, because its private key wasn't found in DOTENV_PRIVATE_KEY_CI or .env.keys");
+        let plain = BTreeMap::from([("REGION".into(), Value::String("eu-west-1".into()))]);
+        assert_eq!(error(plain), "The environment file is invalid, because it wasn't encrypted by dotenvx");
+
+        let other = "2".repeat(64);
+        std::fs::write(dir.path().join(KEYS_FILE), format!("DOTENV_PRIVATE_KEY_CI={}\n", other)).unwrap();
+        assert_eq!(error(values()), "The environment file is invalid, because the value of API_TOKEN can't be decrypted with DOTENV_PRIVATE_KEY_CI");
+    }
+
+    #[test]
+    fn test_private_key_name() {
+        assert_eq!(private_key_name(Path::new(".env")), "DOTENV_PRIVATE_KEY");
+        assert_eq!(private_key_name(Path::new("app/.env.production")), "DOTENV_PRIVATE_KEY_PRODUCTION");
+        assert_eq!(private_key_name(Path::new(".env.ci-local")), "DOTENV_PRIVATE_KEY_CI_LOCAL");
+    }
+}
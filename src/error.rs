@@ -1,5 +1,6 @@
 use std::{fmt, io};
-use age::ssh::ParseRecipientKeyError;
+use std::error::Error;
+use std::path::PathBuf;
 
 
 #[derive(Debug)]
@@ -16,10 +17,134 @@ pub enum AppError {
     EditorError(CommandError),
     /// Failed to parse the environment config file.
     ConfigParseError(toml::de::Error),
+    /// Failed to write settings or variables as TOML.
+    ConfigWriteError(toml::ser::Error),
     /// Failed to run a command.
     RunError(Vec<String>, CommandError),
     /// Value was missing from config file.
     ConfigMissing(String),
+    /// Failed to read or parse a file being imported.
+    ImportError(PathBuf, String),
+    /// An environment already exists with the given name.
+    EnvironmentExists(String),
+    /// A new environment's name can't be used for its file.
+    InvalidEnvironmentName(String),
+    /// A protected environment was used without confirmation.
+    ConfirmationRequired(String),
+    /// A database URL variable couldn't be used to connect to a database.
+    DatabaseUrlError(String, String),
+    /// Failed to read the repo's config file.
+    ConfigReadError(PathBuf, io::Error),
+    /// The repo's config file isn't valid TOML, or has invalid settings.
+    ConfigFileError {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        cause: Box<toml::de::Error>,
+    },
+    /// The HOME environment variable isn't set.
+    MissingHome,
+    /// A key configured for an environment isn't a valid SSH public key.
+    InvalidRecipient {
+        environment: String,
+        /// Where the key is configured, like "the `dev` list in [keys]".
+        list: String,
+        key: String,
+        cause: String,
+    },
+    /// An environment's keys refer to a group that isn't in `[key_groups]`.
+    UnknownKeyGroup {
+        environment: String,
+        group: String,
+    },
+    /// An edited environment refers to a section that isn't configured.
+    UnknownSection {
+        environment: String,
+        section: String,
+    },
+    /// A section's marker line was removed while editing an environment.
+    SectionMarkerRemoved {
+        environment: String,
+        section: String,
+    },
+    /// A value was moved from a section into a part of the environment that
+    /// other keys can decrypt.
+    SectionValueMoved {
+        environment: String,
+        key: String,
+        section: String,
+    },
+    /// A key being added isn't a valid SSH public key.
+    InvalidKey(String, String),
+    /// No key in the config matches a label.
+    UnknownKey(String),
+    /// Failed to read or write a file.
+    IoError(PathBuf, io::Error),
+    /// Failed to prompt the user for input.
+    PromptError(inquire::InquireError),
+    /// Refused to overwrite an existing config file.
+    ConfigExists(PathBuf),
+    /// An explicitly given project root isn't a directory.
+    RepoNotFound(PathBuf),
+    /// No monorepo with `[packages]` was found above the project.
+    NoPackages(PathBuf),
+    /// A package name isn't listed in the monorepo's `[packages]`.
+    UnknownPackage(String),
+    /// Checks failed in one or more of a monorepo's packages.
+    PackageChecksFailed(Vec<String>),
+    /// No task is configured with the given name.
+    UnknownTask(String),
+    /// A hook from the config failed, aborting the operation.
+    HookError(String, CommandError),
+    /// Secret values were found in plaintext by `dev scan`.
+    SecretsFound(usize),
+    /// `dev scan` couldn't decrypt any environments to search for.
+    NoEnvironmentsScanned,
+    /// `dev scan --strict` couldn't decrypt some of the environments.
+    EnvironmentsSkipped(Vec<String>),
+    /// Environments are encrypted for different keys than they should be.
+    KeyAuditFailed(Vec<String>),
+}
+
+impl AppError {
+    /// A suggestion for how the user could fix this error, if there is one.
+    pub fn hint(&self) -> Option<String> {
+        let hint = match self {
+            AppError::GitError(_) => "Run dev inside a directory with a .dev/config.toml or a git repository, or pass --repo".into(),
+            AppError::AgeDecryptError(AgeDecryptError::InvalidSshKey(..)) => {
+                "dev decrypts environments with ~/.ssh/id_ed25519, or the keys in the `identities` setting, which can be created with `ssh-keygen -t ed25519`".into()
+            },
+            AppError::AgeDecryptError(AgeDecryptError::Decrypt(age::DecryptError::NoMatchingKeys)) => {
+                "Your SSH key isn't one of this environment's keys, ask someone with access to add it and re-encrypt the environment".into()
+            },
+            AppError::ConfigFileError { path, line, .. } => format!("Fix line {} of {:?}", line, path),
+            AppError::MissingHome => "Set HOME to your home directory".into(),
+            AppError::InvalidEnvironmentName(_) => "Environment names can't be empty, or contain '/', '..' or '+', which separates sections".into(),
+            AppError::InvalidRecipient { list, .. } => format!(
+                "Keys in {} must be SSH public keys, such as the contents of ~/.ssh/id_ed25519.pub",
+                list,
+            ),
+            AppError::UnknownKeyGroup { group, .. } => format!("Define the {} group in the [key_groups] section of the config", group),
+            AppError::UnknownSection { environment, .. } => format!("Configure sections in [environments.{}.sections]", environment),
+            AppError::SectionMarkerRemoved { .. } => "Keep the `# dev:section` line of each section, and remove the lines below it to empty a section".into(),
+            AppError::SectionValueMoved { .. } => "Move the value back into its section, or delete it and add it again if more people should see it".into(),
+            AppError::InvalidKey(..) => "Keys must be SSH public keys, such as the contents of ~/.ssh/id_ed25519.pub".into(),
+            AppError::UnknownKey(_) => "Run `dev keys list` to see the labels of the keys in [keys]".into(),
+            AppError::ConfigExists(_) => "Edit the existing config file instead".into(),
+            AppError::RepoNotFound(_) => "Check the path passed with --repo or set in DEV_REPO, or the package's path in [packages]".into(),
+            AppError::NoPackages(_) => "List a monorepo's packages in the [packages] section of its root .dev/config.toml".into(),
+            AppError::UnknownPackage(_) => "Check the package names in the monorepo's [packages] section".into(),
+            AppError::UnknownTask(_) => "Run `dev task --list` to see the tasks in [commands.tasks]".into(),
+            AppError::HookError(name, _) => format!("Check the {} hook in the [hooks] section of the config", name),
+            AppError::SecretsFound(_) => "Remove the values and rotate any that were committed, or add their keys to `allow` in the [scan] section of the config if they're meant to be public".into(),
+            AppError::NoEnvironmentsScanned => "Run dev scan with an SSH key that can decrypt at least one environment".into(),
+            AppError::EnvironmentsSkipped(_) => "Run dev scan with an SSH key that can decrypt every environment, or without --strict".into(),
+            AppError::KeyAuditFailed(_) => "Fix [keys] and .dev/developers, then run `dev keys rekey` so only those keys can decrypt the environments".into(),
+            AppError::PromptError(_) => "Interactive prompts need a terminal, pass --yes to skip confirmations".into(),
+            _ => return None,
+        };
+        Some(hint)
+    }
 }
 
 impl fmt::Display for AppError {
@@ -31,12 +156,67 @@ impl fmt::Display for AppError {
             AppError::ChecksumError(cause) => write!(f, "Failed to run checksum: {}", cause),
             AppError::EditorError(cause) => write!(f, "Failed to run editor: {}", cause),
             AppError::ConfigParseError(cause) => write!(f, "Failed to parse config: {}", cause),
+            AppError::ConfigWriteError(cause) => write!(f, "Failed to write TOML: {}", cause),
             AppError::RunError(command, cause) => write!(f, "Failed to run command '{}': {}", command.join(" "), cause),
             AppError::ConfigMissing(setting) => write!(f, "Missing required config value '{}'", setting),
+            AppError::ImportError(path, cause) => write!(f, "Failed to import {:?}: {}", path, cause),
+            AppError::EnvironmentExists(name) => write!(f, "Environment '{}' already exists", name),
+            AppError::InvalidEnvironmentName(name) => write!(f, "Invalid environment name '{}'", name),
+            AppError::DatabaseUrlError(var, cause) => write!(f, "Invalid database URL in '{}': {}", var, cause),
+            AppError::ConfirmationRequired(name) => write!(f, "Environment '{}' is protected and was not confirmed, pass --yes to skip confirmation", name),
+            AppError::ConfigReadError(path, cause) => write!(f, "Failed to read config file {:?}: {}", path, cause),
+            AppError::ConfigFileError { path, line, column, cause } => write!(f, "Invalid config file {:?} at line {}, column {}: {}", path, line, column, cause.message()),
+            AppError::MissingHome => write!(f, "The HOME environment variable is not set"),
+            AppError::InvalidRecipient { environment, key, cause, .. } => write!(f, "Invalid SSH public key for the '{}' environment: '{}': {}", environment, key, cause),
+            AppError::UnknownKeyGroup { environment, group } => write!(f, "The keys for the '{}' environment use an undefined key group '@{}'", environment, group),
+            AppError::UnknownSection { environment, section } => write!(f, "Environment '{}' has no section '{}'", environment, section),
+            AppError::SectionMarkerRemoved { environment, section } => write!(f, "The line starting the '{}' section of '{}' was removed, nothing was saved", section, environment),
+            AppError::SectionValueMoved { environment, key, section } => write!(f, "'{}' was moved out of the '{}' section of '{}', into a part other keys can decrypt, nothing was saved", key, section, environment),
+            AppError::InvalidKey(key, cause) => write!(f, "Invalid SSH public key '{}': {}", key, cause),
+            AppError::UnknownKey(label) => write!(f, "No key matches '{}'", label),
+            AppError::IoError(path, cause) => write!(f, "Failed to access {:?}: {}", path, cause),
+            AppError::PromptError(cause) => write!(f, "Failed to prompt for input: {}", cause),
+            AppError::ConfigExists(path) => write!(f, "Refusing to initialize dev config, {:?} already exists", path),
+            AppError::RepoNotFound(path) => write!(f, "Project root {:?} is not a directory", path),
+            AppError::NoPackages(path) => write!(f, "No monorepo packages are configured for {:?}", path),
+            AppError::UnknownPackage(name) => write!(f, "Package '{}' does not exist", name),
+            AppError::PackageChecksFailed(names) => write!(f, "Checks failed for packages: {}", names.join(", ")),
+            AppError::UnknownTask(name) => write!(f, "Unknown command or task '{}'", name),
+            AppError::HookError(name, cause) => write!(f, "The {} hook failed: {}", name, cause),
+            AppError::SecretsFound(count) => write!(f, "Found {} secret value(s) in plaintext", count),
+            AppError::NoEnvironmentsScanned => write!(f, "No environments could be decrypted, so there are no secrets to scan for"),
+            AppError::EnvironmentsSkipped(names) => write!(f, "Couldn't decrypt environments: {}", names.join(", ")),
+            AppError::KeyAuditFailed(names) => write!(f, "Keys don't match for environments: {}", names.join(", ")),
         }
     }
 }
 
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::GitError(cause)
+            | AppError::ChecksumError(cause)
+            | AppError::EditorError(cause)
+            | AppError::RunError(_, cause)
+            | AppError::HookError(_, cause) => Some(cause),
+            AppError::AgeDecryptError(cause) => Some(cause),
+            AppError::AgeEncryptError(cause) => Some(cause),
+            AppError::ConfigParseError(cause) => Some(cause),
+            AppError::ConfigWriteError(cause) => Some(cause),
+            AppError::ConfigFileError { cause, .. } => Some(cause.as_ref()),
+            AppError::ConfigReadError(_, cause) | AppError::IoError(_, cause) => Some(cause),
+            AppError::PromptError(cause) => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<inquire::InquireError> for AppError {
+    fn from(err: inquire::InquireError) -> Self {
+        Self::PromptError(err)
+    }
+}
+
 impl From<AgeDecryptError> for AppError {
     fn from(err: AgeDecryptError) -> Self {
         Self::AgeDecryptError(err)
@@ -48,6 +228,8 @@ pub enum AgeDecryptError {
     Io(std::io::Error),
     InvalidSshKey(String, std::io::Error),
     Decrypt(age::DecryptError),
+    UnknownEnvironment(String),
+    InvalidFile(String),
 }
 
 impl fmt::Display for AgeDecryptError {
@@ -56,6 +238,18 @@ impl fmt::Display for AgeDecryptError {
             Self::Io(e) => write!(f, "{}", e),
             Self::InvalidSshKey(path, e) => write!(f, "Invalid SSH Key '{}': {}", path, e),
             Self::Decrypt(e) => write!(f, "{}", e),
+            Self::UnknownEnvironment(name) => write!(f, "Environment '{}' does not exist, create it with `dev env create {}`", name, name),
+            Self::InvalidFile(reason) => write!(f, "The environment file is invalid, because {}", reason),
+        }
+    }
+}
+
+impl Error for AgeDecryptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) | Self::InvalidSshKey(_, e) => Some(e),
+            Self::Decrypt(e) => Some(e),
+            Self::UnknownEnvironment(_) | Self::InvalidFile(_) => None,
         }
     }
 }
@@ -82,7 +276,6 @@ impl From<AgeEncryptError> for AppError {
 pub enum AgeEncryptError {
     Io(std::io::Error),
     Encrypt(age::EncryptError),
-    ParseRecipient,
 }
 
 impl fmt::Display for AgeEncryptError {
@@ -90,7 +283,15 @@ impl fmt::Display for AgeEncryptError {
         match self {
             Self::Io(e) => write!(f, "{}", e),
             Self::Encrypt(e) => write!(f, "{}", e),
-            Self::ParseRecipient => write!(f, "Invalid ssh key"),
+        }
+    }
+}
+
+impl Error for AgeEncryptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Encrypt(e) => Some(e),
         }
     }
 }
@@ -107,12 +308,6 @@ impl From<age::EncryptError> for AgeEncryptError {
     }
 }
 
-impl From<ParseRecipientKeyError> for AgeEncryptError {
-    fn from(_err: ParseRecipientKeyError) -> Self {
-        Self::ParseRecipient
-    }
-}
-
 #[derive(Debug)]
 pub enum CommandError {
     /// The command failed to spawn.
@@ -136,6 +331,31 @@ impl fmt::Display for CommandError {
     }
 }
 
-impl std::error::Error for AppError {}
+impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommandError::SpawnError(e) => Some(e),
+            CommandError::FailedError { .. } => None,
+        }
+    }
+}
 
 pub type Result<T> = std::result::Result<T, AppError>;
+
+/// Write an error, followed by each of its causes, one per line. Most errors
+/// already include their cause in their message, so a cause is only written
+/// when its message adds something.
+pub fn write_error_chain<W: io::Write>(err: &dyn Error, out: &mut W) -> io::Result<()> {
+    let mut message = err.to_string();
+    writeln!(out, "{}", message)?;
+    let mut source = err.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            writeln!(out, "  caused by: {}", cause_message)?;
+        }
+        message = cause_message;
+        source = cause.source();
+    }
+    Ok(())
+}
@@ -0,0 +1,386 @@
+//! The format environments are stored in. Each top level entry of an
+//! environment is encrypted separately, with its name left in plaintext, so
+//! that diffs show which variables changed, and unrelated changes on
+//! different branches merge cleanly. Encryption is deterministic: the same
+//! value is always encrypted the same way, so re-encrypting an environment
+//! only changes the entries whose values changed.
+//!
+//! The entries are encrypted with a random data key, which is itself
+//! encrypted with age for the environment's keys. Environments stored before
+//! this, as a single armored age file, can still be read, and are converted
+//! the next time they're encrypted.
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::ssh::{Identity, Recipient};
+use age::{Decryptor, Encryptor};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use toml_edit::{DocumentMut, Item, Key};
+use zeroize::Zeroizing;
+
+use crate::error::*;
+use crate::keys;
+use crate::secrets;
+
+/// The version of the format, which is increased for incompatible changes.
+const VERSION: i64 = 1;
+
+const HEADER: &str = "# Encrypted by dev, edit with `dev config edit`.";
+
+const NONCE_LENGTH: usize = 24;
+
+/// Labels for the keys derived from the data key, and for the kinds of value
+/// that are encrypted with them, so a value can't be moved to another field.
+const ENCRYPTION_LABEL: &[u8] = b"dev entries encryption";
+const NONCE_LABEL: &[u8] = b"dev entries nonce";
+const ENTRY: u8 = 0;
+const TRAILING: u8 = 1;
+
+/// Whether an environment file is in the legacy format, a single age file.
+pub fn is_legacy(content: &[u8]) -> bool {
+    let content = content.trim_ascii_start();
+    content.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") || content.starts_with(b"age-encryption.org/")
+}
+
+/// The age file inside an environment file, whose header lists who can
+/// decrypt it. This is the whole file in the legacy format, or the data key.
+pub fn age_file(content: &[u8]) -> std::io::Result<Vec<u8>> {
+    if is_legacy(content) {
+        return Ok(content.to_vec());
+    }
+    let file = EnvironmentFile::parse(content)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(file.data_key.into_bytes())
+}
+
+/// Decrypt the content of an environment file, in either format.
+pub fn decrypt(content: &[u8], identities: &[Identity]) -> std::result::Result<Zeroizing<Vec<u8>>, AgeDecryptError> {
+    if is_legacy(content) {
+        let decryptor = Decryptor::new(ArmoredReader::new(content))?;
+        let input = decryptor.decrypt(identities.iter().map(|identity| identity as _))?;
+        return Ok(secrets::read_secret(input)?);
+    }
+
+    let file = EnvironmentFile::parse(content)?;
+    let data_key = DataKey::decrypt(&file.data_key, identities)?;
+    let mut plaintext = Zeroizing::new(Vec::new());
+    for (name, value) in &file.entries {
+        let entry = data_key.open(ENTRY, name, value)?;
+        plaintext.extend_from_slice(&entry);
+        if !plaintext.ends_with(b"\n") {
+            plaintext.push(b'\n');
+        }
+    }
+    if let Some(trailing) = &file.trailing {
+        plaintext.extend_from_slice(&data_key.open(TRAILING, "", trailing)?);
+    }
+    Ok(plaintext)
+}
+
+/// Encrypt plaintext TOML content for some keys, returning the content of
+/// the environment file.
+///
+/// The data key of the previous file is kept if it can be decrypted with the
+/// user's identities, and no keys have been removed since it was encrypted,
+/// so that unchanged entries stay the same. When a key is removed, its owner
+/// may still know the old data key, so a new one is used, which changes every
+/// entry. Content that isn't valid TOML can't be split into entries, so it's
+/// stored in the legacy format.
+pub fn encrypt(
+    plaintext: &[u8],
+    recipients: &[Recipient],
+    previous: Option<&[u8]>,
+    identities: &[Identity],
+) -> std::result::Result<Vec<u8>, AgeEncryptError> {
+    let Some((entries, trailing)) = split_entries(plaintext) else {
+        return encrypt_legacy(plaintext, recipients);
+    };
+
+    let tags: Vec<_> = recipients.iter()
+        .map(|recipient| keys::key_tag(&recipient.to_string()))
+        .collect();
+    let previous = previous
+        .filter(|content| !is_legacy(content))
+        .and_then(|content| EnvironmentFile::parse(content).ok())
+        .and_then(|file| {
+            let old = keys::header_recipients(file.data_key.as_bytes()).ok()?;
+            if !old.iter().all(|recipient| recipient.tag.is_some() && tags.contains(&recipient.tag)) {
+                return None;
+            }
+            let data_key = DataKey::decrypt(&file.data_key, identities).ok()?;
+            // The data key only needs encrypting again if keys were added.
+            let encrypted = (old.len() == tags.len()).then_some(file.data_key);
+            Some((data_key, encrypted))
+        });
+    let (data_key, encrypted) = match previous {
+        Some((data_key, Some(encrypted))) => (data_key, encrypted),
+        Some((data_key, None)) => {
+            let encrypted = data_key.encrypt(recipients)?;
+            (data_key, encrypted)
+        },
+        None => {
+            let data_key = DataKey::generate();
+            let encrypted = data_key.encrypt(recipients)?;
+            (data_key, encrypted)
+        },
+    };
+
+    let file = EnvironmentFile {
+        data_key: encrypted,
+        trailing: (!trailing.is_empty()).then(|| data_key.seal(TRAILING, "", trailing.as_bytes())),
+        entries: entries.iter()
+            .map(|(name, entry)| (name.clone(), data_key.seal(ENTRY, name, entry.as_bytes())))
+            .collect(),
+    };
+    Ok(file.to_string().into_bytes())
+}
+
+fn encrypt_legacy(plaintext: &[u8], recipients: &[Recipient]) -> std::result::Result<Vec<u8>, AgeEncryptError> {
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|key| key as _))?;
+    let mut content = Vec::new();
+    let output = ArmoredWriter::wrap_output(&mut content, Format::AsciiArmor)?;
+    let mut output = encryptor.wrap_output(output)?;
+    output.write_all(plaintext)?;
+    output.finish().and_then(|armor| armor.finish())?;
+    Ok(content)
+}
+
+/// A top level entry's name, and its plaintext TOML.
+type Entry = (String, Zeroizing<String>);
+
+/// Split TOML content into its top level entries, each with the comments and
+/// whitespace before it, along with anything after the last entry. Returns
+/// `None` if the content isn't valid TOML.
+fn split_entries(plaintext: &[u8]) -> Option<(Vec<Entry>, Zeroizing<String>)> {
+    let document: DocumentMut = std::str::from_utf8(plaintext).ok()?.parse().ok()?;
+    let mut entries = Vec::new();
+    for (name, _) in document.iter() {
+        let (key, item) = document.as_table().get_key_value(name)?;
+        let mut entry = DocumentMut::new();
+        entry.insert_formatted(key, item.clone());
+        entries.push((name.to_string(), Zeroizing::new(entry.to_string())));
+    }
+    let trailing = document.trailing().as_str().unwrap_or_default();
+    Some((entries, Zeroizing::new(trailing.into())))
+}
+
+/// An environment file, with its values still encrypted.
+struct EnvironmentFile {
+    /// The armored age file containing the data key.
+    data_key: String,
+    /// The comments and whitespace after the last entry.
+    trailing: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl EnvironmentFile {
+    fn parse(content: &[u8]) -> std::result::Result<Self, AgeDecryptError> {
+        let invalid = |reason: &str| AgeDecryptError::InvalidFile(reason.into());
+        let content = std::str::from_utf8(content).map_err(|_| invalid("it isn't valid UTF-8"))?;
+        let document: DocumentMut = content.parse().map_err(|_| invalid("it isn't valid TOML"))?;
+        let metadata = document.get("dev")
+            .and_then(Item::as_table)
+            .ok_or_else(|| invalid("the [dev] table is missing"))?;
+        if metadata.get("version").and_then(Item::as_integer) != Some(VERSION) {
+            return Err(invalid("it was written by a newer version of dev"));
+        }
+        let data_key = metadata.get("data_key")
+            .and_then(Item::as_str)
+            .ok_or_else(|| invalid("the data key is missing"))?;
+        let trailing = metadata.get("trailing").and_then(Item::as_str);
+
+        let mut entries = Vec::new();
+        if let Some(values) = document.get("values").and_then(Item::as_table) {
+            for (name, value) in values.iter() {
+                let value = value.as_str().ok_or_else(|| invalid(&format!("the value of {} isn't a string", name)))?;
+                entries.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        Ok(Self {
+            data_key: data_key.into(),
+            trailing: trailing.map(String::from),
+            entries,
+        })
+    }
+}
+
+impl std::fmt::Display for EnvironmentFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Written by hand, rather than with toml_edit, so the layout never
+        // changes between versions.
+        let mut content = String::new();
+        writeln!(content, "{}", HEADER)?;
+        writeln!(content, "[dev]")?;
+        writeln!(content, "version = {}", VERSION)?;
+        writeln!(content, "data_key = '''\n{}'''", self.data_key)?;
+        if let Some(trailing) = &self.trailing {
+            writeln!(content, "trailing = \"{}\"", trailing)?;
+        }
+        writeln!(content, "\n[values]")?;
+        for (name, value) in &self.entries {
+            writeln!(content, "{} = \"{}\"", Key::new(name.as_str()), value)?;
+        }
+        f.write_str(&content)
+    }
+}
+
+/// The key an environment's entries are encrypted with.
+struct DataKey(Zeroizing<[u8; 32]>);
+
+impl DataKey {
+    fn generate() -> Self {
+        let mut key = Zeroizing::new([0; 32]);
+        rand::rngs::OsRng.fill_bytes(key.as_mut());
+        Self(key)
+    }
+
+    fn decrypt(encrypted: &str, identities: &[Identity]) -> std::result::Result<Self, AgeDecryptError> {
+        let decryptor = Decryptor::new(ArmoredReader::new(encrypted.as_bytes()))?;
+        let mut input = decryptor.decrypt(identities.iter().map(|identity| identity as _))?;
+        let mut key = Zeroizing::new([0; 32]);
+        input.read_exact(key.as_mut())
+            .map_err(|_| AgeDecryptError::InvalidFile("the data key is the wrong length".into()))?;
+        Ok(Self(key))
+    }
+
+    fn encrypt(&self, recipients: &[Recipient]) -> std::result::Result<String, AgeEncryptError> {
+        let content = encrypt_legacy(self.0.as_ref(), recipients)?;
+        Ok(String::from_utf8(content).expect("armored output is ASCII"))
+    }
+
+    fn derive(&self, label: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.0.as_ref()).expect("HMAC accepts any key length");
+        mac.update(label);
+        Zeroizing::new(mac.finalize().into_bytes().to_vec())
+    }
+
+    /// Encrypt one value. The nonce is derived from the value itself, like
+    /// AES-SIV, so the same value always encrypts to the same result, and
+    /// only reveals whether it's changed.
+    fn seal(&self, kind: u8, name: &str, plaintext: &[u8]) -> String {
+        let associated = associated_data(kind, name);
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.derive(NONCE_LABEL)).expect("HMAC accepts any key length");
+        mac.update(&(associated.len() as u64).to_be_bytes());
+        mac.update(&associated);
+        mac.update(plaintext);
+        let hash = mac.finalize().into_bytes();
+        let nonce = XNonce::from_slice(&hash[..NONCE_LENGTH]);
+
+        let cipher = XChaCha20Poly1305::new_from_slice(&self.derive(ENCRYPTION_LABEL)).expect("the key is 32 bytes");
+        let ciphertext = cipher.encrypt(nonce, Payload { msg: plaintext, aad: &associated })
+            .expect("values are much smaller than the cipher's limit");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        STANDARD.encode(sealed)
+    }
+
+    fn open(&self, kind: u8, name: &str, sealed: &str) -> std::result::Result<Zeroizing<Vec<u8>>, AgeDecryptError> {
+        let invalid = || AgeDecryptError::InvalidFile(match kind {
+            ENTRY => format!("the value of {} can't be decrypted", name),
+            _ => "the end of the file can't be decrypted".into(),
+        });
+        let sealed = STANDARD.decode(sealed).map_err(|_| invalid())?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let cipher = XChaCha20Poly1305::new_from_slice(&self.derive(ENCRYPTION_LABEL)).expect("the key is 32 bytes");
+        let plaintext = cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &associated_data(kind, name) })
+            .map_err(|_| invalid())?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+/// Binds an encrypted value to the entry it belongs to, so values can't be
+/// swapped between entries.
+fn associated_data(kind: u8, name: &str) -> Vec<u8> {
+    let mut associated = vec![kind];
+    associated.extend_from_slice(name.as_bytes());
+    associated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_setup::{identities, OTHER_KEY, PUBLIC_KEY};
+    use std::str::FromStr;
+
+    fn recipients(keys: &[&str]) -> Vec<Recipient> {
+        keys.iter().map(|key| Recipient::from_str(key.trim()).unwrap()).collect()
+    }
+
+    /// The lines of the `[values]` table of an environment file.
+    fn values(content: &[u8]) -> Vec<String> {
+        let content = String::from_utf8(content.to_vec()).unwrap();
+        content.split_once("[values]\n").unwrap().1.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let plaintext = b"# Shared\nA = '1'\n\n\"odd key\" = 2\n\n[TABLE]\nx = true\n# The end\n";
+
+        let content = encrypt(plaintext, &recipients(&[PUBLIC_KEY]), None, &[]).unwrap();
+
+        assert!(!is_legacy(&content));
+        assert!(!String::from_utf8_lossy(&content).contains("Shared"));
+        let names: Vec<String> = values(&content).iter().map(|line| line.split(" = ").next().unwrap().into()).collect();
+        assert_eq!(names, vec!["A", "\"odd key\"", "TABLE"]);
+        assert_eq!(decrypt(&content, &identities()).unwrap().as_slice(), plaintext);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let identities = identities();
+        let keys = recipients(&[PUBLIC_KEY]);
+        let first = encrypt(b"A = 1\nB = 2\n", &keys, None, &identities).unwrap();
+
+        let same = encrypt(b"A = 1\nB = 2\n", &keys, Some(&first), &identities).unwrap();
+        assert_eq!(same, first);
+
+        let changed = encrypt(b"A = 1\nB = 3\n", &keys, Some(&first), &identities).unwrap();
+        let (old, new) = (values(&first), values(&changed));
+        assert_eq!(new[0], old[0]);
+        assert_ne!(new[1], old[1]);
+
+        // Adding a key keeps the data key, removing one replaces it.
+        let added = encrypt(b"A = 1\nB = 3\n", &recipients(&[PUBLIC_KEY, OTHER_KEY]), Some(&changed), &identities).unwrap();
+        assert_eq!(values(&added), new);
+        assert_eq!(keys::header_recipients(&age_file(&added).unwrap()).unwrap().len(), 2);
+        let removed = encrypt(b"A = 1\nB = 3\n", &keys, Some(&added), &identities).unwrap();
+        assert_ne!(values(&removed)[0], new[0]);
+    }
+
+    #[test]
+    fn test_legacy() {
+        let legacy = encrypt_legacy(b"A = 1\n", &recipients(&[PUBLIC_KEY])).unwrap();
+
+        assert!(is_legacy(&legacy));
+        assert_eq!(decrypt(&legacy, &identities()).unwrap().as_slice(), b"A = 1\n");
+        assert_eq!(age_file(&legacy).unwrap(), legacy);
+
+        // Content that isn't TOML can't be split into entries.
+        assert!(is_legacy(&encrypt(b"not = toml = ", &recipients(&[PUBLIC_KEY]), None, &[]).unwrap()));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let content = encrypt(b"A = 1\nB = 2\n", &recipients(&[PUBLIC_KEY]), None, &[]).unwrap();
+        let lines = values(&content);
+        let (a, b) = (lines[0].split_once(" = ").unwrap().1, lines[1].split_once(" = ").unwrap().1);
+        let swapped = String::from_utf8(content.clone()).unwrap().replace(a, "SWAP").replace(b, a).replace("SWAP", b);
+
+        let result = decrypt(swapped.as_bytes(), &identities());
+
+        assert!(matches!(result, Err(AgeDecryptError::InvalidFile(reason)) if reason == "the value of A can't be decrypted"));
+    }
+}
@@ -0,0 +1,459 @@
+//! Matching the recipients in the headers of encrypted environment files back
+//! to SSH public keys, to find out who can really decrypt each environment.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use age::armor::ArmoredReader;
+use age::ssh::{ParseRecipientKeyError, Recipient};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use sha2::{Digest, Sha256};
+use toml_edit::{Array, DocumentMut, Item, Table, TableLike};
+
+use crate::error::*;
+use crate::format;
+use crate::{key_label, Config, Environment, Repo, SECTION_SEPARATOR};
+
+/// The file listing the SSH public keys of the project's current developers,
+/// one per line.
+pub const DEVELOPERS_FILE: &str = ".dev/developers";
+
+/// A recipient stanza from the header of an encrypted file.
+#[derive(Debug, PartialEq)]
+pub struct HeaderRecipient {
+    /// The type of recipient, like `ssh-ed25519`.
+    pub kind: String,
+    /// The tag identifying an SSH recipient's public key.
+    pub tag: Option<String>,
+}
+
+/// Read the recipients from the header of an encrypted environment file,
+/// without decrypting it.
+pub fn read_recipients(path: &Path) -> std::io::Result<Vec<HeaderRecipient>> {
+    let content = std::fs::read(path)?;
+    header_recipients(&format::age_file(&content)?)
+}
+
+/// Label a recipient with the comment of the matching key in `keys`, or
+/// with its type and tag if none match.
+pub fn recipient_label(recipient: &HeaderRecipient, keys: &[String]) -> String {
+    match &recipient.tag {
+        Some(tag) => keys.iter()
+            .find(|key| key_tag(key).as_ref() == Some(tag))
+            .map(|key| key_label(key))
+            .unwrap_or_else(|| format!("unknown {} key {}", recipient.kind, tag)),
+        None => format!("{} recipient", recipient.kind),
+    }
+}
+
+/// Every key in the config, from `[keys]`, `[key_groups]` and the sections
+/// of each environment, including `@group` references.
+pub fn config_keys(config: &Config) -> Vec<String> {
+    let sections = config.environments.iter().flatten()
+        .flat_map(|(_, environment)| environment.sections.values());
+    config.keys.iter().flat_map(|keys| keys.values())
+        .chain(config.key_groups.iter().flat_map(|groups| groups.values()))
+        .chain(sections)
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// Read the recipients from the header of an age file.
+pub fn header_recipients(content: &[u8]) -> std::io::Result<Vec<HeaderRecipient>> {
+    let mut reader = BufReader::new(ArmoredReader::new(content));
+    let mut recipients = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 || line.starts_with(b"---") {
+            return Ok(recipients);
+        }
+
+        let Some(stanza) = line.strip_prefix(b"-> ") else { continue };
+        let stanza = String::from_utf8_lossy(stanza);
+        let mut args = stanza.split_whitespace();
+        let Some(kind) = args.next() else { continue };
+        // age adds stanzas with random types to keep parsers flexible, which
+        // don't belong to anyone.
+        if kind.ends_with("-grease") {
+            continue;
+        }
+        let tag = match kind {
+            "ssh-ed25519" | "ssh-rsa" => args.next().map(String::from),
+            _ => None,
+        };
+        recipients.push(HeaderRecipient { kind: kind.into(), tag });
+    }
+}
+
+/// The tag age uses to identify an SSH public key in a file's header, which
+/// is the start of the SHA-256 hash of the key.
+pub fn key_tag(key: &str) -> Option<String> {
+    let encoded = key.split_whitespace().nth(1)?;
+    let decoded = STANDARD.decode(encoded).ok()?;
+    let hash = Sha256::digest(decoded);
+    Some(STANDARD_NO_PAD.encode(&hash[..4]))
+}
+
+/// Parse an SSH public key, describing why it isn't valid if it can't be.
+pub fn parse_recipient(key: &str) -> std::result::Result<Recipient, String> {
+    Recipient::from_str(key).map_err(|err| match err {
+        ParseRecipientKeyError::Ignore => "this type of key isn't supported".into(),
+        ParseRecipientKeyError::Invalid(reason) => reason.into(),
+        ParseRecipientKeyError::RsaModulusTooLarge => "the RSA key is too large".into(),
+        ParseRecipientKeyError::RsaModulusTooSmall => "the RSA key is too small to be secure, it must be at least 2048 bits".into(),
+        ParseRecipientKeyError::Unsupported(kind) => format!("{} keys aren't supported, use ed25519 or RSA", kind),
+    })
+}
+
+/// Whether two keys are the same, ignoring their comments.
+pub fn same_key(a: &str, b: &str) -> bool {
+    a.trim() == b.trim() || key_tag(a).is_some_and(|tag| key_tag(b) == Some(tag))
+}
+
+/// Whether a key is the one a label refers to, by its comment, its tag, or
+/// the whole key.
+pub fn key_matches(key: &str, label: &str) -> bool {
+    key_label(key) == label || key_tag(key).as_deref() == Some(label) || key.trim() == label.trim()
+}
+
+/// The keys in the project's `.dev/developers` file, or `None` if it doesn't
+/// exist. Blank lines and `#` comments are skipped.
+pub fn read_developers(repo: &Repo) -> Result<Option<Vec<String>>> {
+    let path = repo.path().join(DEVELOPERS_FILE);
+    let content = match read_optional(&path)? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    let keys = content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    Ok(Some(keys))
+}
+
+/// Add keys to the project's `.dev/developers` file, creating it if needed.
+/// Keys that are already listed are skipped.
+pub fn add_developers(repo: &Repo, keys: &[String]) -> Result<()> {
+    let path = repo.path().join(DEVELOPERS_FILE);
+    let mut content = read_optional(&path)?.unwrap_or_default();
+    let existing = read_developers(repo)?.unwrap_or_default();
+    for key in keys {
+        if existing.iter().any(|existing| same_key(existing, key)) {
+            continue;
+        }
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(key.trim());
+        content.push('\n');
+    }
+    std::fs::write(&path, content).map_err(|e| AppError::IoError(path, e))
+}
+
+/// Remove the keys matching a label from the project's `.dev/developers`
+/// file, keeping any comments.
+pub fn remove_developers(repo: &Repo, label: &str) -> Result<()> {
+    let path = repo.path().join(DEVELOPERS_FILE);
+    let Some(content) = read_optional(&path)? else { return Ok(()) };
+    let content: String = content.lines()
+        .filter(|line| line.trim().starts_with('#') || !key_matches(line, label))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    std::fs::write(&path, content).map_err(|e| AppError::IoError(path, e))
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(AppError::IoError(path.into(), e)),
+    }
+}
+
+/// The `[keys]` section of a project's `.dev/config.toml`, edited in place so
+/// the rest of the file keeps its formatting and comments.
+pub struct ConfigKeys {
+    path: PathBuf,
+    document: DocumentMut,
+}
+
+impl ConfigKeys {
+    pub fn open(repo: &Repo) -> Result<Self> {
+        let path = repo.path().join(".dev/config.toml");
+        let document = match read_optional(&path)? {
+            Some(content) => {
+                // Loading the config first reports any syntax errors with
+                // their location.
+                Config::load(&path)?;
+                content.parse().expect("config was already parsed")
+            },
+            None => DocumentMut::new(),
+        };
+        Ok(Self { path, document })
+    }
+
+    /// Add a key to an environment's list. If the environment doesn't have
+    /// its own list yet, it's created from the keys it currently uses, so
+    /// that adding a key doesn't remove the default ones.
+    pub fn add(&mut self, environment: &str, key: &str, current: &[String]) {
+        let keys = self.keys_table();
+        if !keys.contains_key(environment) {
+            keys.insert(environment, Item::Value(current.iter().map(String::as_str).collect::<Array>().into()));
+        }
+        let Some(list) = keys.get_mut(environment).and_then(Item::as_array_mut) else { return };
+        if !list.iter().any(|existing| existing.as_str().is_some_and(|existing| same_key(existing, key))) {
+            list.push(key.trim());
+        }
+    }
+
+    /// Remove the keys matching a label from every environment's list, every
+    /// section's list and every key group, returning the names of the lists
+    /// that changed, with groups as `@group` and sections as `env+section`.
+    pub fn remove(&mut self, label: &str) -> Vec<String> {
+        fn remove_from(table: &mut dyn TableLike, label: &str, prefix: &str, changed: &mut Vec<String>) {
+            for (name, list) in table.iter_mut() {
+                let Some(list) = list.as_array_mut() else { continue };
+                let before = list.len();
+                list.retain(|key| !key.as_str().is_some_and(|key| key_matches(key, label)));
+                if list.len() != before {
+                    changed.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+
+        let mut changed = Vec::new();
+        for (section, prefix) in [("keys", ""), ("key_groups", "@")] {
+            let Some(table) = self.document.get_mut(section).and_then(Item::as_table_like_mut) else { continue };
+            remove_from(table, label, prefix, &mut changed);
+        }
+        let Some(environments) = self.document.get_mut("environments").and_then(Item::as_table_like_mut) else { return changed };
+        for (name, environment) in environments.iter_mut() {
+            let Some(sections) = environment.get_mut("sections").and_then(Item::as_table_like_mut) else { continue };
+            let prefix = format!("{}{}", name, SECTION_SEPARATOR);
+            remove_from(sections, label, &prefix, &mut changed);
+        }
+        changed
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, self.document.to_string())
+            .map_err(|e| AppError::IoError(self.path.clone(), e))
+    }
+
+    fn keys_table(&mut self) -> &mut dyn TableLike {
+        let keys = self.document.entry("keys").or_insert_with(|| Item::Table(Table::new()));
+        keys.as_table_like_mut().expect("[keys] was parsed as a table")
+    }
+}
+
+/// The environments whose keys, or whose sections' keys, differ between two
+/// versions of a project's config, so they need to be re-encrypted.
+pub fn changed_environments(before: &Repo, after: &Repo) -> std::io::Result<Vec<String>> {
+    // Sections have their own keys, which can change without the
+    // environment's, such as when a group only they use changes.
+    let keys = |repo: &Repo, name: &str| {
+        let environment = repo.get_environment(name.into());
+        let sections = environment.sections();
+        std::iter::once(environment).chain(sections)
+            .map(|environment| (environment.display_name(), environment.get_key_strings().ok()))
+            .collect::<Vec<_>>()
+    };
+    Ok(before.environment_names()?
+        .into_iter()
+        .filter(|name| keys(before, name) != keys(after, name))
+        .collect())
+}
+
+/// Differences between who an environment is configured for, and who can
+/// actually decrypt it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Audit {
+    /// Keys in `[keys]` that the file isn't encrypted for, so it needs to be
+    /// re-encrypted.
+    pub missing: Vec<String>,
+    /// Recipients in the file that aren't in `[keys]`.
+    pub unexpected: Vec<String>,
+    /// Recipients in the file that are neither in `.dev/developers` nor
+    /// anywhere in the config, like the keys of people who have left.
+    pub former: Vec<String>,
+}
+
+impl Audit {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.former.is_empty()
+    }
+
+    /// Compare an environment's keys with the recipients of its file. Keys
+    /// are labelled with their comment, preferring the one in
+    /// `.dev/developers`.
+    pub fn new(environment: &Environment<'_>, developers: Option<&[String]>) -> Result<Self> {
+        let path = environment.path();
+        let recipients = read_recipients(&path).map_err(|e| AppError::IoError(path, e))?;
+        let configured = environment.get_key_strings()?;
+
+        // Keys in the config that aren't a developer's, like CI's, are still
+        // current.
+        let config_keys = config_keys(environment.repo.config());
+        let mut known = developers.unwrap_or_default().to_vec();
+        known.extend(config_keys.iter().cloned());
+        let label = |recipient: &HeaderRecipient| recipient_label(recipient, &known);
+        let is_in = |recipient: &HeaderRecipient, keys: &[String]| recipient.tag.is_some()
+            && keys.iter().any(|key| key_tag(key) == recipient.tag);
+
+        let mut audit = Self::default();
+        for key in &configured {
+            if !recipients.iter().any(|recipient| recipient.tag.is_some() && recipient.tag == key_tag(key)) {
+                audit.missing.push(key_label(key));
+            }
+        }
+        for recipient in &recipients {
+            if !is_in(recipient, &configured) {
+                audit.unexpected.push(label(recipient));
+            }
+            if let Some(developers) = developers {
+                if !is_in(recipient, developers) && !is_in(recipient, &config_keys) {
+                    audit.former.push(label(recipient));
+                }
+            }
+        }
+        Ok(audit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_setup::*;
+    use crate::Config;
+
+    #[test]
+    fn test_read_recipients() {
+        let setup = TestSetup::new();
+        let env = setup.env();
+        env.encrypt(b"").unwrap();
+
+        let recipients = read_recipients(&env.path()).unwrap();
+
+        assert_eq!(recipients, vec![HeaderRecipient {
+            kind: "ssh-ed25519".into(),
+            tag: key_tag(PUBLIC_KEY.trim()),
+        }]);
+    }
+
+    #[test]
+    fn test_key_tag() {
+        assert_eq!(key_tag(PUBLIC_KEY.trim()).unwrap().len(), 6);
+        assert_eq!(key_tag(PUBLIC_KEY.trim()), key_tag("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMKcaO+SsZg1StalnVVX+nei1oqLT/ShJTleGpucGUt5 other"));
+        assert_ne!(key_tag(PUBLIC_KEY.trim()), key_tag(OTHER_KEY));
+        assert_eq!(key_tag("not a key"), None);
+    }
+
+    #[test]
+    fn test_audit() {
+        let setup = TestSetup::new();
+        setup.env().encrypt(b"").unwrap();
+        let path = setup.repo.path();
+        std::fs::write(path.join(DEVELOPERS_FILE), format!("# Team\n{}\n", OTHER_KEY)).unwrap();
+        // The environment was encrypted for the test key, which has since been
+        // replaced in the config.
+        let config = Config {
+            keys: Some([("default".into(), vec![OTHER_KEY.into()])].into()),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(path.into(), path.to_str().unwrap().into(), config);
+
+        let developers = read_developers(&repo).unwrap().unwrap();
+        let audit = Audit::new(&repo.get_environment("local".into()), Some(&developers)).unwrap();
+
+        assert_eq!(audit, Audit {
+            missing: vec!["alice@laptop".into()],
+            unexpected: vec![format!("unknown ssh-ed25519 key {}", key_tag(PUBLIC_KEY.trim()).unwrap())],
+            former: vec![format!("unknown ssh-ed25519 key {}", key_tag(PUBLIC_KEY.trim()).unwrap())],
+        });
+        assert!(!audit.is_ok());
+
+        // Keys that aren't a developer's, like CI's, are fine while they're
+        // in the config.
+        let config = Config {
+            keys: Some([("default".into(), vec![PUBLIC_KEY.trim().into()])].into()),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(path.into(), path.to_str().unwrap().into(), config);
+        let audit = Audit::new(&repo.get_environment("local".into()), Some(&developers)).unwrap();
+        assert!(audit.is_ok());
+    }
+
+    #[test]
+    fn test_audit_ok() {
+        let setup = TestSetup::new();
+        setup.env().encrypt(b"").unwrap();
+
+        let audit = Audit::new(&setup.env(), None).unwrap();
+
+        assert!(audit.is_ok());
+    }
+
+    #[test]
+    fn test_parse_recipient() {
+        assert!(parse_recipient(PUBLIC_KEY.trim()).is_ok());
+        assert_eq!(parse_recipient("ssh-ed25519 notbase64").unwrap_err(), "invalid SSH recipient");
+        assert!(parse_recipient("ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBFzlyM0fUj1DEoRyDcLYhdrTwYkaJ5zIwHJjXZ27yimo2UZJ6Yn7wFbz+FpTf8bAgbxqrMh1oXmjf7EhXU9Wp0w=").is_err());
+    }
+
+    #[test]
+    fn test_config_keys() {
+        let setup = TestSetup::new();
+        let path = setup.repo.path().join(".dev/config.toml");
+        std::fs::write(&path, format!("# Team keys\n[keys]\ndefault = [\"{}\"]\n", PUBLIC_KEY.trim())).unwrap();
+
+        let mut config = ConfigKeys::open(&setup.repo).unwrap();
+        config.add("default", OTHER_KEY, &[]);
+        config.add("prd", OTHER_KEY, &[PUBLIC_KEY.trim().into()]);
+        config.save().unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# Team keys\n"));
+        let loaded = Config::load(&path).unwrap().keys.unwrap();
+        assert_eq!(loaded["default"], vec![PUBLIC_KEY.trim().to_string(), OTHER_KEY.into()]);
+        assert_eq!(loaded["prd"], vec![PUBLIC_KEY.trim().to_string(), OTHER_KEY.into()]);
+
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str(&format!("[key_groups]\nbackend = [\"{}\"]\n", OTHER_KEY));
+        content.push_str(&format!("[environments.prd.sections]\ndb = [\"@backend\", \"{}\"]\n", OTHER_KEY));
+        std::fs::write(&path, content).unwrap();
+        let mut config = ConfigKeys::open(&setup.repo).unwrap();
+        assert_eq!(config.remove("alice@laptop"), vec!["default", "prd", "@backend", "prd+db"]);
+        assert!(config.remove("alice@laptop").is_empty());
+        config.save().unwrap();
+        let sections = &Config::load(&path).unwrap().environments.unwrap()["prd"].sections;
+        assert_eq!(sections["db"], vec!["@backend"]);
+
+        // Changing a group that only a section uses changes the environment.
+        let open = || Repo::from_parts(setup.repo.path().into(), setup.repo.home.clone(), Config::load(&path).unwrap());
+        std::fs::write(setup.repo.path().join(".dev/env.age.prd"), "").unwrap();
+        std::fs::write(&path, "[keys]\nprd = []\n[key_groups]\nbackend = []\n[environments.prd.sections]\ndb = [\"@backend\"]\n").unwrap();
+        let before = open();
+        std::fs::write(&path, format!("[keys]\nprd = []\n[key_groups]\nbackend = [\"{}\"]\n[environments.prd.sections]\ndb = [\"@backend\"]\n", OTHER_KEY)).unwrap();
+        assert_eq!(changed_environments(&before, &open()).unwrap(), vec!["prd"]);
+        assert!(changed_environments(&before, &before).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_developers() {
+        let setup = TestSetup::new();
+        std::fs::write(setup.repo.path().join(DEVELOPERS_FILE), "# Team\n").unwrap();
+
+        add_developers(&setup.repo, &[OTHER_KEY.into(), PUBLIC_KEY.trim().into()]).unwrap();
+        add_developers(&setup.repo, &[OTHER_KEY.into()]).unwrap();
+        assert_eq!(read_developers(&setup.repo).unwrap().unwrap(), vec![OTHER_KEY.to_string(), PUBLIC_KEY.trim().into()]);
+
+        remove_developers(&setup.repo, "testkey").unwrap();
+        let content = std::fs::read_to_string(setup.repo.path().join(DEVELOPERS_FILE)).unwrap();
+        assert_eq!(content, format!("# Team\n{}\n", OTHER_KEY));
+    }
+}
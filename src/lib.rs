@@ -0,0 +1,2334 @@
+//! The missing tool for 12 factor development environments.
+//!
+//! This crate provides the library behind the `dev` command line tool, so that
+//! other tools can work with a project's encrypted environments directly. A
+//! [`Repo`] is discovered from the current directory, and gives access to the
+//! project's [`Config`] and each of its [`Environment`]s.
+//!
+//! ```no_run
+//! use std::collections::BTreeMap;
+//!
+//! let repo = dev::Repo::new()?;
+//! for name in repo.environment_names()? {
+//!     println!("{}", name);
+//! }
+//!
+//! let environment = repo.get_environment("local".into());
+//! let mut values = environment.values()?;
+//! values.insert("DEBUG".into(), toml::Value::Boolean(true));
+//! environment.set_values(&values)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod error;
+pub mod db;
+pub mod dotenvx;
+pub mod format;
+pub mod keys;
+pub mod redact;
+pub mod scan;
+pub mod secrets;
+pub mod sops;
+
+#[cfg(feature = "python")]
+mod python;
+
+// The command line interface is normally part of the binary, but the Python
+// module also provides it, as the `dev` script in the wheel.
+#[cfg(feature = "python")]
+#[path = "cli.rs"]
+mod cli;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, IsTerminal, Seek, SeekFrom, Write};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+use age::ssh::{Identity, Recipient};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use toml::{self, Value};
+use zeroize::Zeroizing;
+
+use error::*;
+use redact::Redactor;
+use secrets::Secrets;
+
+/// Commands configured in the `[commands]` section of the config.
+#[derive(Deserialize, Serialize, Default)]
+pub struct Commands {
+    /// The command run by `dev start`.
+    pub start: Option<String>,
+    /// A wrapper command, run with the real command as its arguments.
+    pub shell: Option<String>,
+    /// Named commands run by `dev check`.
+    pub checks: Option<BTreeMap<String, String>>,
+    /// Project specific commands, run by `dev task <name>` or `dev <name>`.
+    pub tasks: Option<BTreeMap<String, Task>>,
+}
+
+/// A task from the `[commands.tasks]` section of the config, either as just
+/// its command, or as a table with a description.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Task {
+    Command(String),
+    Detailed {
+        command: String,
+        description: Option<String>,
+    },
+}
+
+impl Task {
+    /// The bash script run for the task, with any extra arguments as `$@`.
+    pub fn command(&self) -> &str {
+        match self {
+            Task::Command(command) | Task::Detailed { command, .. } => command,
+        }
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        match self {
+            Task::Command(_) => None,
+            Task::Detailed { description, .. } => description.as_deref(),
+        }
+    }
+}
+
+/// Colours that can be used to highlight an environment's banner.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+}
+
+impl Color {
+    fn ansi_code(&self) -> u8 {
+        match self {
+            Color::Red => 31,
+            Color::Green => 32,
+            Color::Yellow => 33,
+            Color::Blue => 34,
+            Color::Magenta => 35,
+            Color::Cyan => 36,
+        }
+    }
+}
+
+/// Settings for `dev scan`, from the `[scan]` section of the config.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct ScanConfig {
+    /// Keys whose values are meant to be public, so they aren't reported when
+    /// they appear in plaintext.
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// Commands run at points in dev's lifecycle, from the `[hooks]` section of
+/// the config. Each is a bash script run in the project root, with the
+/// environment's variables set.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct Hooks {
+    /// Run before `dev run` and tasks.
+    pub pre_run: Option<String>,
+    /// Run before the `dev start` command.
+    pub pre_start: Option<String>,
+    /// Run after the `dev start` command exits, with its exit code.
+    pub post_start: Option<String>,
+    /// Run after `dev config edit` changes an environment.
+    pub post_edit: Option<String>,
+    /// Run before an environment is encrypted, with its new values.
+    pub pre_encrypt: Option<String>,
+}
+
+/// A point in dev's lifecycle where a hook can be run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreRun,
+    PreStart,
+    PostStart,
+    PostEdit,
+    PreEncrypt,
+}
+
+impl Hook {
+    /// The hook's name in the `[hooks]` section.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hook::PreRun => "pre_run",
+            Hook::PreStart => "pre_start",
+            Hook::PostStart => "post_start",
+            Hook::PostEdit => "post_edit",
+            Hook::PreEncrypt => "pre_encrypt",
+        }
+    }
+}
+
+impl Hooks {
+    /// The script configured for a hook, if there is one.
+    pub fn get(&self, hook: Hook) -> Option<&str> {
+        let script = match hook {
+            Hook::PreRun => &self.pre_run,
+            Hook::PreStart => &self.pre_start,
+            Hook::PostStart => &self.post_start,
+            Hook::PostEdit => &self.post_edit,
+            Hook::PreEncrypt => &self.pre_encrypt,
+        };
+        script.as_deref()
+    }
+}
+
+/// When to colour environment banners.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Colour output to a terminal, unless `NO_COLOR` is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Which environments need confirmation before they're used.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmMode {
+    /// Only protected environments.
+    #[default]
+    Protected,
+    /// Every environment, protected or not.
+    Always,
+    /// No environments, as if `--yes` was always passed.
+    Never,
+}
+
+/// Settings for a single environment, from the `[environments.<name>]`
+/// section of the config.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct EnvironmentConfig {
+    /// Require confirmation before running commands in this environment.
+    #[serde(default)]
+    pub protected: bool,
+    /// The colour used to highlight the environment's banner.
+    pub color: Option<Color>,
+    /// A message shown whenever the environment is used.
+    pub banner: Option<String>,
+    /// Start database sessions as read-only by default.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Parts of the environment that are stored in their own files, each
+    /// encrypted for its own keys instead of the environment's, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sections: BTreeMap<String, Vec<String>>,
+    /// A file encrypted by SOPS, relative to the project root, that the
+    /// environment's values are read from until it has its own file.
+    pub sops: Option<PathBuf>,
+}
+
+/// Settings from the `[database]` section of the config.
+#[derive(Deserialize, Serialize, Default)]
+pub struct DatabaseConfig {
+    /// The environment variable containing the database URL used by `dev db`.
+    pub url_var: Option<String>,
+}
+
+/// A project's `.dev/config.toml` file.
+#[derive(Deserialize, Serialize, Default)]
+pub struct Config {
+    pub commands: Option<Commands>,
+    /// The SSH public keys each environment is encrypted for, by environment
+    /// name, with `default` used for any environment not listed. An entry
+    /// like `@backend` stands for every key in that group of `[key_groups]`.
+    pub keys: Option<BTreeMap<String, Vec<String>>>,
+    /// Named lists of SSH public keys, which `[keys]` can refer to.
+    pub key_groups: Option<BTreeMap<String, Vec<String>>>,
+    pub environments: Option<BTreeMap<String, EnvironmentConfig>>,
+    pub database: Option<DatabaseConfig>,
+    /// Use keys and the shell command from the nearest `.dev/config.toml`
+    /// above this one, when they aren't set here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inherit: bool,
+    /// The packages in a monorepo, by name, with their directories relative
+    /// to this config's project root.
+    pub packages: Option<BTreeMap<String, PathBuf>>,
+    /// SSH private keys used to decrypt environments, instead of
+    /// `~/.ssh/id_ed25519`. A leading `~/` refers to the home directory.
+    pub identities: Option<Vec<String>>,
+    /// The environment used when `-e` isn't given.
+    pub default_environment: Option<String>,
+    /// The editor used by `dev config edit`, instead of `$EDITOR`.
+    pub editor: Option<String>,
+    /// When to colour environment banners.
+    pub color: Option<ColorMode>,
+    /// Which environments need confirmation before they're used.
+    pub confirm: Option<ConfirmMode>,
+    pub hooks: Option<Hooks>,
+    /// Replace secret values in the output of commands with their names, as
+    /// if `--redact` was always passed.
+    pub redact: Option<bool>,
+    pub scan: Option<ScanConfig>,
+}
+
+impl Config {
+    /// Read and parse a config file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::ConfigReadError(path.into(), e))?;
+        toml::from_str(&content).map_err(|cause| {
+            let offset = cause.span().map(|span| span.start).unwrap_or(0);
+            let (line, column) = line_column(&content, offset);
+            AppError::ConfigFileError {
+                path: path.into(),
+                line,
+                column,
+                cause: Box::new(cause),
+            }
+        })
+    }
+
+    /// Fill in personal settings that aren't set in this config from the
+    /// user's own config file. Keys, environments, commands and other
+    /// settings that describe the project only ever come from its own config,
+    /// so a user's config can't change who environments are encrypted for.
+    pub fn merge_defaults(&mut self, defaults: Config) {
+        self.identities = self.identities.take().or(defaults.identities);
+        self.default_environment = self.default_environment.take().or(defaults.default_environment);
+        self.editor = self.editor.take().or(defaults.editor);
+        self.color = self.color.or(defaults.color);
+        self.confirm = self.confirm.or(defaults.confirm);
+        self.hooks = self.hooks.take().or(defaults.hooks);
+        self.redact = self.redact.or(defaults.redact);
+    }
+
+    /// Fill in keys, key groups and the shell command from a parent project's
+    /// config, without replacing any that are set in this one.
+    pub fn inherit_from(&mut self, parent: &Config) {
+        fn inherit_map(map: &mut Option<BTreeMap<String, Vec<String>>>, parent: &Option<BTreeMap<String, Vec<String>>>) {
+            if let Some(parent) = parent {
+                let map = map.get_or_insert_with(BTreeMap::new);
+                for (name, parent_keys) in parent {
+                    map.entry(name.clone()).or_insert_with(|| parent_keys.clone());
+                }
+            }
+        }
+
+        inherit_map(&mut self.keys, &parent.keys);
+        inherit_map(&mut self.key_groups, &parent.key_groups);
+
+        let parent_shell = parent.commands.as_ref().and_then(|commands| commands.shell.clone());
+        if parent_shell.is_some() {
+            let commands = self.commands.get_or_insert_with(Commands::default);
+            if commands.shell.is_none() {
+                commands.shell = parent_shell;
+            }
+        }
+    }
+}
+
+/// The path of the user's own config file, in `$XDG_CONFIG_HOME` if it's set,
+/// or `~/.config` otherwise.
+pub fn user_config_path(home: &str, xdg_config_home: Option<OsString>) -> PathBuf {
+    // The XDG spec says relative paths should be ignored.
+    let config_home = xdg_config_home
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| Path::new(home).join(".config"));
+    config_home.join("dev/config.toml")
+}
+
+/// Find the nearest directory at or above `start` containing a `.dev`
+/// directory.
+fn find_dev_root(start: &Path) -> Option<PathBuf> {
+    start.ancestors()
+        .find(|dir| dir.join(".dev").is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Find the nearest config file strictly above a project root, along with
+/// the directory it belongs to.
+fn find_parent_config(root: &Path) -> Option<(PathBuf, PathBuf)> {
+    root.ancestors()
+        .skip(1)
+        .find(|dir| dir.join(".dev/config.toml").is_file())
+        .map(|dir| (dir.to_path_buf(), dir.join(".dev/config.toml")))
+}
+
+/// Convert a byte offset into a 1-based line and column number.
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// The names of keys that were added, removed or changed between two sets of
+/// values, sorted by name.
+pub fn changed_keys(old: &BTreeMap<String, Value>, new: &BTreeMap<String, Value>) -> Vec<String> {
+    let mut keys: Vec<String> = old.keys()
+        .chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// The exit code to use to pass on a child process's exit status. Processes
+/// killed by a signal follow the shell convention of 128 plus the signal.
+pub fn exit_code(status: std::process::ExitStatus) -> i32 {
+    status.code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// How the root directory of a project was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepoSource {
+    /// Passed explicitly, e.g. with `--repo`.
+    Explicit,
+    /// Set by the `DEV_REPO` environment variable.
+    EnvVar,
+    /// The nearest directory above the current one with a `.dev` directory.
+    DevDir,
+    /// The top level of the git repository containing the current directory.
+    Git,
+    /// A package selected from a monorepo's `[packages]`.
+    Package,
+    /// Given directly to [`Repo::from_parts`].
+    Parts,
+}
+
+impl std::fmt::Display for RepoSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoSource::Explicit => write!(f, "given with --repo"),
+            RepoSource::EnvVar => write!(f, "set by DEV_REPO"),
+            RepoSource::DevDir => write!(f, "nearest directory containing .dev"),
+            RepoSource::Git => write!(f, "top level of the git repository"),
+            RepoSource::Package => write!(f, "package in the monorepo"),
+            RepoSource::Parts => write!(f, "given directly"),
+        }
+    }
+}
+
+/// Where a config file was looked for when loading a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFileKind {
+    /// The project's own `.dev/config.toml`.
+    Project,
+    /// A parent project's config, used by `inherit = true`.
+    Inherited,
+    /// The user's `~/.config/dev/config.toml`.
+    User,
+}
+
+impl std::fmt::Display for ConfigFileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigFileKind::Project => write!(f, "project"),
+            ConfigFileKind::Inherited => write!(f, "inherited"),
+            ConfigFileKind::User => write!(f, "user"),
+        }
+    }
+}
+
+/// A config file that was looked for when loading a project.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub kind: ConfigFileKind,
+    pub path: PathBuf,
+    /// Whether the file existed and was loaded.
+    pub loaded: bool,
+}
+
+/// A project using the dev tool, along with its config.
+pub struct Repo {
+    home: String,
+    repo_path: PathBuf,
+    source: RepoSource,
+    config: Config,
+    config_files: Vec<ConfigFile>,
+    /// Skip confirmation prompts for protected environments.
+    assume_yes: bool,
+    /// Redact secret values from the output of commands.
+    redact: bool,
+}
+
+impl Repo {
+    /// Find the project containing the current directory, and load its
+    /// config.
+    pub fn new() -> Result<Self> {
+        Self::open(None)
+    }
+
+    /// Load the project at the given root directory, or find the project
+    /// containing the current directory if it's `None`.
+    ///
+    /// Without an explicit root, the `DEV_REPO` environment variable is used
+    /// if it's set, then the nearest directory containing `.dev`,
+    /// and finally the top level of the current git repository.
+    pub fn open(repo_path: Option<PathBuf>) -> Result<Self> {
+        let (repo_path, source) = Self::find_root(repo_path)?;
+        let home = std::env::var("HOME").map_err(|_| AppError::MissingHome)?;
+        let mut config_files = Vec::new();
+        let config = Self::load_all_config(&repo_path, &home, &mut config_files)?;
+        let mut repo = Self::from_parts(repo_path, home, config);
+        repo.source = source;
+        repo.config_files = config_files;
+        Ok(repo)
+    }
+
+    /// Load a project's config, with the user's config merged underneath it,
+    /// recording each file that was looked for.
+    fn load_all_config(repo_path: &Path, home: &str, files: &mut Vec<ConfigFile>) -> Result<Config> {
+        let mut config = Self::load_config(repo_path, ConfigFileKind::Project, files)?;
+
+        let user_path = user_config_path(home, std::env::var_os("XDG_CONFIG_HOME"));
+        let loaded = user_path.is_file();
+        if loaded {
+            config.merge_defaults(Config::load(&user_path)?);
+        }
+        files.push(ConfigFile { kind: ConfigFileKind::User, path: user_path, loaded });
+
+        Ok(config)
+    }
+
+    /// Load the config for a project root, which is empty if the root has no
+    /// config file. Settings are inherited from the parent project's config
+    /// if the config asks for it.
+    fn load_config(repo_path: &Path, kind: ConfigFileKind, files: &mut Vec<ConfigFile>) -> Result<Config> {
+        let config_path = repo_path.join(".dev/config.toml");
+        let loaded = config_path.is_file();
+        files.push(ConfigFile { kind, path: config_path.clone(), loaded });
+        if !loaded {
+            return Ok(Config::default());
+        }
+
+        let mut config = Config::load(&config_path)?;
+        if config.inherit {
+            if let Some((parent_path, _)) = find_parent_config(repo_path) {
+                let parent = Self::load_config(&parent_path, ConfigFileKind::Inherited, files)?;
+                config.inherit_from(&parent);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The packages of the monorepo this project belongs to, by name, with
+    /// their absolute paths. The monorepo's root is the nearest project at or
+    /// above this one with `[packages]` in its config.
+    pub fn packages(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let mut root = self.repo_path.clone();
+        let mut packages = self.config.packages.clone();
+        while packages.is_none() {
+            let Some((parent_path, config_path)) = find_parent_config(&root) else {
+                return Err(AppError::NoPackages(self.repo_path.clone()));
+            };
+            packages = Config::load(&config_path)?.packages;
+            root = parent_path;
+        }
+
+        let packages = packages.unwrap_or_default()
+            .into_iter()
+            .map(|(name, path)| (name, root.join(path)))
+            .collect();
+        Ok(packages)
+    }
+
+    /// Open one of the packages in this project's monorepo by name, keeping
+    /// the same settings as this repo.
+    pub fn package(&self, name: &str) -> Result<Repo> {
+        let path = self.packages()?
+            .remove(name)
+            .ok_or_else(|| AppError::UnknownPackage(name.into()))?;
+        if !path.is_dir() {
+            return Err(AppError::RepoNotFound(path));
+        }
+
+        let mut config_files = Vec::new();
+        let config = Self::load_all_config(&path, &self.home, &mut config_files)?;
+        Ok(Self {
+            home: self.home.clone(),
+            repo_path: path,
+            source: RepoSource::Package,
+            config,
+            config_files,
+            assume_yes: self.assume_yes,
+            redact: self.redact,
+        })
+    }
+
+    /// Create a repo from an already known path and config. The home directory
+    /// is used to find the SSH key used for decryption.
+    pub fn from_parts(repo_path: PathBuf, home: String, config: Config) -> Self {
+        Self {
+            home,
+            repo_path,
+            source: RepoSource::Parts,
+            config,
+            config_files: Vec::new(),
+            assume_yes: false,
+            redact: false,
+        }
+    }
+
+    /// The root directory of the project.
+    pub fn path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// How the root directory of the project was found.
+    pub fn source(&self) -> RepoSource {
+        self.source
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The config files that were looked for when loading the project, in
+    /// order of precedence.
+    pub fn config_files(&self) -> &[ConfigFile] {
+        &self.config_files
+    }
+
+    /// The environment used when none is given, which is `local` unless the
+    /// config sets `default_environment`.
+    pub fn default_environment(&self) -> &str {
+        self.config.default_environment.as_deref().unwrap_or("local")
+    }
+
+    /// The SSH private keys used to decrypt environments.
+    pub fn identity_paths(&self) -> Vec<PathBuf> {
+        match &self.config.identities {
+            Some(identities) => identities.iter()
+                .map(|path| match path.strip_prefix("~/") {
+                    Some(path) => Path::new(&self.home).join(path),
+                    None => path.into(),
+                })
+                .collect(),
+            None => vec![Path::new(&self.home).join(".ssh/id_ed25519")],
+        }
+    }
+
+    /// Read the SSH private keys used to decrypt environments.
+    fn identities(&self) -> std::result::Result<Vec<Identity>, AgeDecryptError> {
+        let mut identities = Vec::new();
+        for private_key_path in self.identity_paths() {
+            let private_key = File::open(&private_key_path)
+                .map_err(|e| AgeDecryptError::InvalidSshKey(private_key_path.display().to_string(), e))?;
+            let private_key = BufReader::new(private_key);
+            identities.push(Identity::from_buffer(private_key, None)?);
+        }
+        Ok(identities)
+    }
+
+    /// Skip confirmation prompts for protected environments, for use in
+    /// non-interactive contexts.
+    pub fn set_assume_yes(&mut self, assume_yes: bool) {
+        self.assume_yes = assume_yes;
+    }
+
+    pub fn assume_yes(&self) -> bool {
+        self.assume_yes
+    }
+
+    /// Redact secret values from the output of commands, as well as when the
+    /// config sets `redact = true`.
+    pub fn set_redact(&mut self, redact: bool) {
+        self.redact = redact;
+    }
+
+    pub fn redact(&self) -> bool {
+        self.redact || self.config.redact.unwrap_or(false)
+    }
+
+    fn find_root(explicit: Option<PathBuf>) -> Result<(PathBuf, RepoSource)> {
+        let (path, source) = match explicit {
+            Some(path) => (path, RepoSource::Explicit),
+            None => match std::env::var_os("DEV_REPO").filter(|path| !path.is_empty()) {
+                Some(path) => (PathBuf::from(path), RepoSource::EnvVar),
+                None => {
+                    let current_dir = std::env::current_dir()
+                        .map_err(|e| AppError::IoError(".".into(), e))?;
+                    return match find_dev_root(&current_dir) {
+                        Some(path) => Ok((path, RepoSource::DevDir)),
+                        None => Ok((Self::get_repo_path()?, RepoSource::Git)),
+                    };
+                },
+            },
+        };
+
+        // Explicit roots are made absolute, so they still work if a command
+        // changes directory.
+        let path = std::path::absolute(&path)
+            .map_err(|e| AppError::IoError(path.clone(), e))?;
+        if !path.is_dir() {
+            return Err(AppError::RepoNotFound(path));
+        }
+        Ok((path, source))
+    }
+
+    fn get_repo_path() -> Result<PathBuf> {
+        let output = Command::new("git")
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .map_err(CommandError::SpawnError)
+            .map_err(AppError::GitError)?;
+
+        if !output.status.success() {
+            return Err(AppError::GitError(CommandError::FailedError {
+                status: output.status,
+                stderr: Some(String::from_utf8_lossy(&output.stderr).to_string()),
+            }));
+        }
+
+        // Paths aren't necessarily UTF-8, so git's output is used as is,
+        // minus the trailing newline.
+        let mut path = output.stdout;
+        while path.last().is_some_and(u8::is_ascii_whitespace) {
+            path.pop();
+        }
+        Ok(PathBuf::from(OsString::from_vec(path)))
+    }
+
+    /// Get an environment by name. The environment doesn't need to exist yet.
+    pub fn get_environment(&self, name: String) -> Environment<'_> {
+        Environment {
+            name,
+            section: None,
+            repo: self,
+        }
+    }
+
+    /// List the names of all environments that have an encrypted file in the
+    /// `.dev` directory, sorted by name.
+    pub fn environment_names(&self) -> std::io::Result<Vec<String>> {
+        let dev_dir = self.repo_path.join(".dev");
+        if !dev_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dev_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str().and_then(|name| name.strip_prefix("env.age.")) {
+                // Sections are stored alongside their environment.
+                if !name.contains(SECTION_SEPARATOR) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+/// Convert a TOML value into the string used for its environment variable.
+pub fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        Value::Datetime(value) => value.to_string(),
+        value => serde_json::to_string(&value).unwrap(),
+    }
+}
+
+/// Get a human readable label for an SSH public key, using the key's comment
+/// if it has one.
+pub fn key_label(key: &str) -> String {
+    let mut parts = key.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (_, _, Some(comment)) => comment.into(),
+        (_, Some(key), None) if key.len() > 16 => format!("...{}", &key[key.len() - 16..]),
+        _ => key.into(),
+    }
+}
+
+/// Separates an environment's name from a section's in the name of the
+/// section's file.
+pub(crate) const SECTION_SEPARATOR: char = '+';
+
+/// The line that starts each section when an environment is edited.
+const SECTION_MARKER: &str = "# dev:section ";
+
+/// The plaintext of an environment's main file, or of one of its sections.
+type Part = (Option<String>, Zeroizing<Vec<u8>>);
+
+/// A named set of environment variables, stored encrypted in the project's
+/// `.dev/env.age.<name>` file. Some of its variables can be kept in sections,
+/// stored in `.dev/env.age.<name>+<section>` and encrypted for fewer keys.
+pub struct Environment<'a> {
+    name: String,
+    /// The section of the environment this refers to, if it's only a part of
+    /// the environment.
+    section: Option<String>,
+    repo: &'a Repo,
+}
+
+impl<'a> Environment<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the section, if this is one of the environment's sections.
+    pub fn section_name(&self) -> Option<&str> {
+        self.section.as_deref()
+    }
+
+    /// The environment's name, followed by the section's if this is one, as
+    /// in its file name.
+    pub fn display_name(&self) -> String {
+        match &self.section {
+            Some(section) => format!("{}{}{}", self.name, SECTION_SEPARATOR, section),
+            None => self.name.clone(),
+        }
+    }
+
+    /// A section of this environment.
+    pub fn section(&self, section: &str) -> Environment<'a> {
+        Environment {
+            name: self.name.clone(),
+            section: Some(section.into()),
+            repo: self.repo,
+        }
+    }
+
+    /// The sections configured for this environment, or none if this is
+    /// already a section.
+    pub fn sections(&self) -> Vec<Environment<'a>> {
+        if self.section.is_some() {
+            return Vec::new();
+        }
+        self.config().sections.keys()
+            .map(|section| self.section(section))
+            .collect()
+    }
+
+    /// The path of the environment's encrypted file.
+    pub fn path(&self) -> PathBuf {
+        let name = format!(".dev/env.age.{}", self.display_name());
+        self.repo.repo_path.join(name)
+    }
+
+    /// Whether the environment's encrypted file exists.
+    pub fn exists(&self) -> bool {
+        self.path().is_file()
+    }
+
+    /// The environment's settings, or the defaults if it has none.
+    pub fn config(&self) -> EnvironmentConfig {
+        self.repo.config.environments.as_ref()
+            .and_then(|environments| environments.get(&self.name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Write the environment's banner, if it has one, highlighting it with the
+    /// environment's colour when `color` is set.
+    fn write_banner<W: Write>(&self, out: &mut W, color: bool) -> std::io::Result<()> {
+        let config = self.config();
+        let banner = match config.banner {
+            Some(banner) => banner,
+            None if config.protected => format!("You are using the protected '{}' environment", self.name),
+            None => return Ok(()),
+        };
+        let width = banner.lines()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let border = "=".repeat(width + 4);
+
+        let color = config.color
+            .or(config.protected.then_some(Color::Red))
+            .filter(|_| color);
+        if let Some(color) = color {
+            write!(out, "\x1b[1;{}m", color.ansi_code())?;
+        }
+        writeln!(out, "{}", border)?;
+        for line in banner.lines() {
+            writeln!(out, "| {:width$} |", line, width = width)?;
+        }
+        write!(out, "{}", border)?;
+        if color.is_some() {
+            write!(out, "\x1b[0m")?;
+        }
+        writeln!(out)
+    }
+
+    /// Show the environment's banner, and if the environment is protected,
+    /// make the user type its name before continuing. This should be called
+    /// before running any command that uses the environment.
+    pub fn confirm(&self) -> Result<()> {
+        let mut stderr = std::io::stderr();
+        let color = match self.repo.config.color.unwrap_or_default() {
+            ColorMode::Auto => stderr.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        };
+        let _ = self.write_banner(&mut stderr, color);
+
+        let required = match self.repo.config.confirm.unwrap_or_default() {
+            ConfirmMode::Protected => self.config().protected,
+            ConfirmMode::Always => true,
+            ConfirmMode::Never => false,
+        };
+        if !required || self.repo.assume_yes {
+            return Ok(());
+        }
+
+        let answer = inquire::Text::new(&format!("Type '{}' to continue:", self.name))
+            .prompt()
+            .map_err(|_| AppError::ConfirmationRequired(self.name.clone()))?;
+        if answer.trim() != self.name {
+            return Err(AppError::ConfirmationRequired(self.name.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt the environment's file, returning its plaintext TOML content.
+    /// The plaintext is only ever held in memory, and is overwritten when
+    /// it's dropped.
+    pub fn decrypt(&self) -> std::result::Result<Zeroizing<Vec<u8>>, AgeDecryptError> {
+        let env_path = self.path();
+        let identities = self.repo.identities()?;
+
+        if !std::fs::exists(&env_path)? {
+            // Values from a SOPS file are converted to TOML, so encrypting
+            // them again, e.g. by editing them, moves them into the
+            // environment's own file.
+            if let Some(path) = self.sops_path() {
+                let content = std::fs::read(&path)?;
+                let values = sops::decrypt(&path, &content, &identities)?;
+                let content = toml::to_string_pretty(&values).map_err(|e| {
+                    AgeDecryptError::InvalidFile(format!("its values can't be converted to TOML: {}", e))
+                })?;
+                return Ok(Zeroizing::new(content.into_bytes()));
+            }
+            return Err(AgeDecryptError::UnknownEnvironment(self.name.clone()));
+        }
+
+        let content = std::fs::read(env_path)?;
+        format::decrypt(&content, &identities)
+    }
+
+    /// The SOPS file the environment is read from, if it's configured and
+    /// the environment doesn't have its own file.
+    pub fn sops_path(&self) -> Option<PathBuf> {
+        if self.section.is_some() || self.exists() {
+            return None;
+        }
+        self.config().sops.map(|path| self.repo.repo_path.join(path))
+    }
+
+    /// The entries in `[keys]` for this environment, falling back to the
+    /// default keys. These can be keys, or `@group` references.
+    pub fn key_entries(&self) -> &'a [String] {
+        if let Some(section) = &self.section {
+            let environment = self.repo.config.environments.as_ref()
+                .and_then(|environments| environments.get(&self.name));
+            return environment
+                .and_then(|environment| environment.sections.get(section))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+        }
+
+        if let Some(keys) = &self.repo.config.keys {
+            let keys = keys.get(&self.name)
+                .or_else(|| keys.get("default"));
+            if let Some(keys) = keys {
+                return keys;
+            }
+        }
+
+        &[]
+    }
+
+    /// The public keys that this environment is encrypted for, with groups
+    /// from `[key_groups]` expanded, and each key only listed once.
+    pub fn get_key_strings(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = Vec::new();
+        for entry in self.key_entries() {
+            let group = match entry.strip_prefix('@') {
+                Some(group) => self.repo.config.key_groups.as_ref()
+                    .and_then(|groups| groups.get(group))
+                    .ok_or_else(|| AppError::UnknownKeyGroup {
+                        environment: self.name.clone(),
+                        group: group.into(),
+                    })?,
+                None => std::slice::from_ref(entry),
+            };
+            for key in group {
+                // The same key can have different comments in different
+                // places, so comments are ignored.
+                if !keys.iter().any(|existing| keys::same_key(existing, key)) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Where a key from `get_key_strings` is configured, like "the `dev`
+    /// list in [keys]", for pointing at it in errors.
+    fn key_list(&self, key: &str) -> String {
+        for entry in self.key_entries() {
+            match entry.strip_prefix('@') {
+                Some(group) => {
+                    let listed = self.repo.config.key_groups.as_ref()
+                        .and_then(|groups| groups.get(group))
+                        .is_some_and(|keys| keys.iter().any(|listed| listed == key));
+                    if listed {
+                        return format!("the `{}` list in [key_groups]", group);
+                    }
+                },
+                None if entry == key => break,
+                None => {},
+            }
+        }
+
+        match &self.section {
+            Some(section) => format!("the `{}` list in [environments.{}.sections]", section, self.name),
+            None => {
+                let configured = self.repo.config.keys.as_ref()
+                    .is_some_and(|keys| keys.contains_key(&self.name));
+                let name = if configured { self.name.as_str() } else { "default" };
+                format!("the `{}` list in [keys]", name)
+            },
+        }
+    }
+
+    /// The parsed public keys that this environment is encrypted for.
+    pub fn get_keys(&self) -> Result<Vec<Recipient>> {
+        let keys = self.get_key_strings()?;
+        let mut result = Vec::with_capacity(keys.len());
+        for key in keys {
+            let recipient = keys::parse_recipient(&key)
+                .map_err(|cause| AppError::InvalidRecipient {
+                    environment: self.name.clone(),
+                    list: self.key_list(&key),
+                    key,
+                    cause,
+                })?;
+            result.push(recipient);
+        }
+        Ok(result)
+    }
+
+    /// Check that the environment's name can be used for a new file: it
+    /// can't be empty, leave the `.dev` directory, or look like a section.
+    pub fn check_name(&self) -> Result<()> {
+        let name = &self.name;
+        if name.is_empty() || name.contains(['/', SECTION_SEPARATOR]) || name.contains("..") {
+            return Err(AppError::InvalidEnvironmentName(name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Create a new environment with no variables.
+    pub fn create(&self) -> Result<()> {
+        self.check_name()?;
+        if self.exists() {
+            return Err(AppError::EnvironmentExists(self.name.clone()));
+        }
+        self.encrypt(b"")?;
+        Ok(())
+    }
+
+    /// Copy all variables in this environment into a new environment,
+    /// encrypting them with the destination environment's keys. Each section
+    /// is copied into the same section of the destination, which must be
+    /// configured, so its variables aren't exposed to more keys.
+    pub fn copy_to(&self, destination: &Environment<'_>) -> Result<()> {
+        destination.check_name()?;
+        if destination.exists() {
+            return Err(AppError::EnvironmentExists(destination.name.clone()));
+        }
+        let sections: Vec<_> = self.sections().into_iter()
+            .filter(Environment::exists)
+            .collect();
+        for section in &sections {
+            let name = section.section_name().unwrap_or_default();
+            if !destination.config().sections.contains_key(name) {
+                return Err(AppError::ConfigMissing(format!("environments.{}.sections.{}", destination.name, name)));
+            }
+        }
+
+        let plaintext = self.decrypt()?;
+        destination.encrypt(&plaintext)?;
+        for section in &sections {
+            let plaintext = section.decrypt()?;
+            destination.section(section.section_name().unwrap_or_default()).encrypt(&plaintext)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the encrypted file for this environment, along with its
+    /// sections.
+    pub fn delete(&self) -> Result<()> {
+        if !self.exists() {
+            return Err(AgeDecryptError::UnknownEnvironment(self.name.clone()).into());
+        }
+        for section in self.sections() {
+            if section.exists() {
+                std::fs::remove_file(section.path())
+                    .map_err(AgeEncryptError::Io)?;
+            }
+        }
+        std::fs::remove_file(self.path())
+            .map_err(AgeEncryptError::Io)?;
+        Ok(())
+    }
+
+    /// Encrypt plaintext TOML content, replacing the environment's encrypted
+    /// file.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<()> {
+        let keys = self.get_keys()?;
+        if let Some(script) = self.hook(Hook::PreEncrypt) {
+            let new = parse_values(plaintext)?;
+            let old = if self.exists() { self.values()? } else { Secrets::new() };
+            let changed = changed_keys(&old, &new);
+            let values = new.iter()
+                .map(|(key, value)| (key.clone(), value_to_string(value.clone())))
+                .collect();
+            self.run_hook_script(Hook::PreEncrypt, script, &values, vec![
+                ("DEV_CHANGED_KEYS".into(), changed.join(" ")),
+            ])?;
+        }
+        self.write_encrypted(&keys, plaintext)?;
+        Ok(())
+    }
+
+    /// Re-encrypt the environment for its current keys, so that keys removed
+    /// from the config can no longer decrypt it. The values don't change, so
+    /// the `pre_encrypt` hook isn't run.
+    pub fn rekey(&self) -> Result<()> {
+        let content = self.decrypt()?;
+        let keys = self.get_keys()?;
+        self.write_encrypted(&keys, &content)?;
+        Ok(())
+    }
+
+    fn write_encrypted(&self, keys: &[Recipient], plaintext: &[u8]) -> std::result::Result<(), AgeEncryptError> {
+        let env_path = self.path();
+        // The previous file's data key is reused when possible, so that only
+        // changed values are encrypted differently, which needs the user's
+        // keys. Without them, everything is encrypted with a new data key.
+        let previous = std::fs::read(&env_path).ok();
+        let identities = self.repo.identities().unwrap_or_default();
+
+        let content = format::encrypt(plaintext, keys, previous.as_deref(), &identities)?;
+        std::fs::write(env_path, content)?;
+
+        Ok(())
+    }
+
+    fn calculate_checksum(&self, path: &Path) -> Result<String> {
+        let output = Command::new("sha256sum")
+            .arg("--")
+            .arg(path)
+            .output()
+            .map_err(CommandError::SpawnError)
+            .map_err(AppError::ChecksumError)?;
+
+        if !output.status.success() {
+            return Err(AppError::ChecksumError(CommandError::FailedError {
+                status: output.status,
+                stderr: Some(String::from_utf8_lossy(&output.stderr).to_string()),
+            }));
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        let hash = output.split_whitespace().next().unwrap_or_default();
+        Ok(hash.into())
+    }
+
+    fn run_editor(&self, path: &Path) -> Result<()> {
+        let editor = self.repo.config.editor.clone()
+            .or_else(|| std::env::var("EDITOR").ok())
+            .unwrap_or_else(|| "vi".into());
+
+        // The path is passed as an argument, rather than being quoted into
+        // the script, so it can't be misinterpreted by the shell.
+        let status = Command::new("bash")
+            .args(["-c", &format!("{} -- \"$1\"", editor), "bash"])
+            .arg(path)
+            .status()
+            .map_err(CommandError::SpawnError)
+            .map_err(AppError::EditorError)?;
+
+        if !status.success() {
+            return Err(AppError::EditorError(CommandError::FailedError {
+                status,
+                stderr: None,
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt the environment and open it in the user's `$EDITOR`,
+    /// encrypting it again if anything was changed. The sections that can be
+    /// decrypted follow the rest of the environment, each starting with a
+    /// `# dev:section <name>` line, and the others are left as they are.
+    pub fn edit(&self) -> Result<()> {
+        let mut parts = vec![(None, self.decrypt()?)];
+        let mut locked = Vec::new();
+        for section in self.sections() {
+            match section.decrypt_if_allowed()? {
+                Some(plaintext) => parts.push((section.section, plaintext)),
+                None => locked.extend(section.section),
+            }
+        }
+        if !locked.is_empty() {
+            eprintln!("Not editing sections you can't decrypt: {}", locked.join(", "));
+        }
+
+        let plaintext = join_sections(&parts);
+        let name = self.path().file_name().map(OsString::from).unwrap_or_else(|| "dev.toml".into());
+        let file = EditFile::new(&name, &plaintext).map_err(AgeDecryptError::Io)?;
+
+        let old_hash = self.calculate_checksum(file.path())?;
+        // Old values are only needed to tell the post_edit hook what changed,
+        // and an environment with invalid content should still be editable.
+        let old_values = match self.hook(Hook::PostEdit) {
+            Some(_) => parse_sections(&parts)?,
+            None => Secrets::new(),
+        };
+
+        self.run_editor(file.path())?;
+
+        let new_hash = self.calculate_checksum(file.path())?;
+
+        // Only encrypt the file if the content has changed from the original,
+        // since re-encrypting it with a new data key, e.g. after a key was
+        // removed from the config, would change every value in the file.
+        if old_hash != new_hash {
+            // Editors often replace the file rather than writing to it, so
+            // it's read again by path.
+            let plaintext = Zeroizing::new(file.read().map_err(AgeEncryptError::Io)?);
+            let new_parts = self.split_sections(&plaintext, &parts)?;
+            self.check_moved_values(&parts, &new_parts)?;
+            for ((section, old), (_, new)) in parts.iter().zip(&new_parts) {
+                // Sections that weren't changed aren't re-encrypted either.
+                if old.trim_ascii_end() == new.trim_ascii_end() {
+                    continue;
+                }
+                match section {
+                    Some(section) => self.section(section).encrypt(new)?,
+                    None => self.encrypt(new)?,
+                }
+            }
+
+            if self.hook(Hook::PostEdit).is_some() {
+                let new_values = parse_sections(&new_parts)?;
+                let changed = changed_keys(&old_values, &new_values);
+                self.run_hook(Hook::PostEdit, vec![
+                    ("DEV_CHANGED_KEYS".into(), changed.join(" ")),
+                ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Split edited content back into the sections it was made from, in the
+    /// same order. Every section must still have its marker line, since
+    /// otherwise its values would end up in the part before it.
+    fn split_sections(&self, plaintext: &[u8], parts: &[Part]) -> Result<Vec<Part>> {
+        let mut split: Vec<Part> = vec![(None, Zeroizing::new(Vec::new()))];
+        for line in plaintext.split_inclusive(|byte| *byte == b'\n') {
+            let marker = line.strip_prefix(SECTION_MARKER.as_bytes())
+                .map(|name| String::from_utf8_lossy(name.trim_ascii()).into_owned());
+            match marker {
+                Some(name) => {
+                    if !parts.iter().any(|(section, _)| section.as_ref() == Some(&name)) {
+                        return Err(AppError::UnknownSection { environment: self.name.clone(), section: name });
+                    }
+                    split.push((Some(name), Zeroizing::new(Vec::new())));
+                },
+                None => split.last_mut().expect("there's always a part").1.extend_from_slice(line),
+            }
+        }
+
+        parts.iter()
+            .map(|(section, _)| {
+                let (_, new) = split.iter().rev().find(|(name, _)| name == section)
+                    .ok_or_else(|| AppError::SectionMarkerRemoved {
+                        environment: self.name.clone(),
+                        section: section.clone().unwrap_or_default(),
+                    })?;
+                Ok((section.clone(), new.clone()))
+            })
+            .collect()
+    }
+
+    /// Check that no value was moved out of a section into a part of the
+    /// environment that keys outside the section can decrypt.
+    fn check_moved_values(&self, old: &[Part], new: &[Part]) -> Result<()> {
+        let old_names: Vec<BTreeSet<String>> = old.iter().map(|(_, content)| value_names(content)).collect();
+        for (index, (section, content)) in new.iter().enumerate() {
+            let gained: Vec<String> = value_names(content).difference(&old_names[index]).cloned().collect();
+            if gained.is_empty() {
+                continue;
+            }
+            let keys = self.part(section).get_key_strings()?;
+            for ((from, _), names) in old.iter().zip(&old_names) {
+                let Some(from) = from else { continue };
+                let Some(key) = gained.iter().find(|key| names.contains(*key)) else { continue };
+                let allowed = self.section(from).get_key_strings()?;
+                if keys.iter().any(|key| !allowed.contains(key)) {
+                    return Err(AppError::SectionValueMoved {
+                        environment: self.name.clone(),
+                        key: key.clone(),
+                        section: from.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The environment itself, or one of its sections.
+    fn part(&self, section: &Option<String>) -> Environment<'a> {
+        match section {
+            Some(section) => self.section(section),
+            None => self.repo.get_environment(self.name.clone()),
+        }
+    }
+
+    /// Decrypt a section, or return `None` if it can't be decrypted with the
+    /// user's keys. A section without a file yet is empty.
+    fn decrypt_if_allowed(&self) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        if !self.exists() {
+            return Ok(Some(Zeroizing::new(Vec::new())));
+        }
+        match self.decrypt() {
+            Ok(plaintext) => Ok(Some(plaintext)),
+            Err(AgeDecryptError::Decrypt(age::DecryptError::NoMatchingKeys)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Decrypt and parse the environment's values, including those from any
+    /// sections that can be decrypted.
+    pub fn values(&self) -> Result<Secrets<Value>> {
+        let mut values = parse_values(&self.decrypt()?)?;
+        for section in self.sections() {
+            if let Some(plaintext) = section.decrypt_if_allowed()? {
+                let mut section_values = parse_values(&plaintext)?;
+                values.append(&mut section_values);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Replace all of the environment's values, encrypting them. Values that
+    /// are in a section stay in that section, and sections that can't be
+    /// decrypted are left as they are.
+    pub fn set_values(&self, values: &BTreeMap<String, Value>) -> Result<()> {
+        let mut remaining = values.clone();
+        for section in self.sections() {
+            let Some(plaintext) = section.decrypt_if_allowed()? else { continue };
+            let old = parse_values(&plaintext)?;
+            let new: BTreeMap<String, Value> = old.keys()
+                .filter_map(|key| remaining.remove_entry(key))
+                .collect();
+            if *old != new {
+                section.write_values(&new)?;
+            }
+        }
+        self.write_values(&remaining)
+    }
+
+    fn write_values(&self, values: &BTreeMap<String, Value>) -> Result<()> {
+        let content = Zeroizing::new(toml::to_string_pretty(values).map_err(AppError::ConfigWriteError)?);
+        self.encrypt(content.as_bytes())
+    }
+
+    /// The environment's values, as they would be set as environment variables.
+    pub fn string_values(&self) -> Result<Secrets<String>> {
+        let values = self.values()?
+            .iter()
+            .map(|(key, value)| (key.clone(), value_to_string(value.clone())))
+            .collect();
+        Ok(values)
+    }
+
+    /// A filter replacing the environment's values in command output.
+    pub fn redactor(&self) -> Result<Redactor> {
+        let values = self.string_values()?;
+        Ok(Redactor::new(&values))
+    }
+
+    /// Run a given command with all defined environment variables, returning
+    /// its exit code, so the caller can exit with it.
+    pub fn exec(&self, path: &str, args: Vec<&str>) -> Result<i32> {
+        self.exec_with_envs(path, args, Vec::new())
+    }
+
+    /// The same as `exec`, but with extra environment variables that take
+    /// precedence over the environment's own values.
+    pub fn exec_with_envs(&self, path: &str, args: Vec<&str>, envs: Vec<(String, String)>) -> Result<i32> {
+        let status = self.run_with_envs(path, args, envs)?;
+        Ok(exit_code(status))
+    }
+
+    /// The same as `exec_with_envs`, but returning the command's exit status,
+    /// including the signal that killed it.
+    pub fn run_with_envs(&self, path: &str, args: Vec<&str>, envs: Vec<(String, String)>) -> Result<ExitStatus> {
+        let mut command = Command::new(path);
+        for arg in &args {
+            command.arg(arg);
+        }
+
+        let values = self.string_values()?;
+        command.envs(values.iter());
+        command.envs(envs);
+
+        // Output is only piped through dev when it needs redacting, so
+        // commands otherwise still see a terminal.
+        let redactor = match self.repo.redact() {
+            true => {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+                Some(Redactor::new(&values))
+            },
+            false => None,
+        };
+        drop(values);
+        if let Some(limit) = secrets::original_core_limit() {
+            // SAFETY: setrlimit is async-signal-safe, so it can be called
+            // between fork and exec.
+            unsafe {
+                command.pre_exec(move || {
+                    libc::setrlimit(libc::RLIMIT_CORE, &limit);
+                    Ok(())
+                });
+            }
+        }
+
+        let err = match command.spawn() {
+            Ok(mut child) => {
+                // Like `system()`, interrupts are left to the command while
+                // waiting for it, so dev can still finish up afterwards.
+                let _ignore = IgnoreInterrupts::new();
+                let status = match &redactor {
+                    Some(redactor) => redactor.wait(&mut child),
+                    None => child.wait(),
+                };
+                match status {
+                    Ok(status) => return Ok(status),
+                    Err(err) => err,
+                }
+            },
+            Err(err) => err,
+        };
+
+        let mut all_args = vec![path];
+        all_args.extend(args);
+        let all_args = all_args.into_iter()
+            .map(|s| s.into())
+            .collect();
+
+        Err(AppError::RunError(all_args, CommandError::SpawnError(err)))
+    }
+
+    fn hook(&self, hook: Hook) -> Option<&str> {
+        self.repo.config.hooks.as_ref()?.get(hook)
+    }
+
+    /// Run a hook from the config, if it's set, with the environment's values
+    /// and extra context variables. A failing hook returns an error, so the
+    /// operation it's part of can be aborted.
+    pub fn run_hook(&self, hook: Hook, context: Vec<(String, String)>) -> Result<()> {
+        match self.hook(hook) {
+            Some(script) => {
+                let values = self.string_values()?;
+                self.run_hook_script(hook, script, &values, context)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn run_hook_script(
+        &self,
+        hook: Hook,
+        script: &str,
+        values: &BTreeMap<String, String>,
+        context: Vec<(String, String)>,
+    ) -> Result<()> {
+        let status = Command::new("bash")
+            .args(["-ce", script, hook.name()])
+            .current_dir(self.repo.path())
+            .envs(values)
+            .env("DEV_HOOK", hook.name())
+            .env("DEV_ENVIRONMENT", &self.name)
+            .envs(context)
+            .status()
+            .map_err(|err| AppError::HookError(hook.name().into(), CommandError::SpawnError(err)))?;
+
+        if !status.success() {
+            return Err(AppError::HookError(hook.name().into(), CommandError::FailedError {
+                status,
+                stderr: None,
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// Join an environment's sections into one file for editing.
+fn join_sections(parts: &[Part]) -> Zeroizing<Vec<u8>> {
+    let mut plaintext = Zeroizing::new(Vec::new());
+    for (section, content) in parts {
+        if let Some(section) = section {
+            if !plaintext.is_empty() && !plaintext.ends_with(b"\n") {
+                plaintext.push(b'\n');
+            }
+            writeln!(plaintext, "\n{}{}", SECTION_MARKER, section).unwrap();
+        }
+        plaintext.extend_from_slice(content);
+    }
+    // Sections are only added when there are any, so an environment without
+    // them is edited exactly as it's stored.
+    if parts.len() > 1 && !plaintext.ends_with(b"\n") {
+        plaintext.push(b'\n');
+    }
+    plaintext
+}
+
+/// Parse and merge the values of an environment's sections.
+fn parse_sections(parts: &[Part]) -> Result<Secrets<Value>> {
+    let mut values = Secrets::new();
+    for (_, content) in parts {
+        let mut section_values = parse_values(content)?;
+        values.append(&mut section_values);
+    }
+    Ok(values)
+}
+
+/// The names of the values in some environment content. Content that isn't
+/// valid TOML is read line by line, so values are still found in it.
+fn value_names(content: &[u8]) -> BTreeSet<String> {
+    if let Ok(values) = parse_values(content) {
+        return values.keys().cloned().collect();
+    }
+    String::from_utf8_lossy(content).lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, _)| name.trim().trim_matches(['"', '\'']).to_string())
+        .filter(|name| !name.is_empty() && !name.starts_with('#'))
+        .collect()
+}
+
+/// Parse the plaintext TOML content of an environment.
+fn parse_values(plaintext: &[u8]) -> Result<Secrets<Value>> {
+    let content = std::str::from_utf8(plaintext)
+        .map_err(|e| AgeDecryptError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    toml::from_str(content).map_err(AppError::ConfigParseError)
+}
+
+/// Directories for plaintext files being edited, in order of preference. The
+/// user's runtime directory and `/dev/shm` are normally in memory, so the
+/// plaintext never reaches a disk. A memfd isn't used, because editors need a
+/// real path, that they can replace with a new file when saving.
+fn edit_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        if runtime_dir.is_absolute() {
+            dirs.push(runtime_dir);
+        }
+    }
+    dirs.push("/dev/shm".into());
+    dirs.push(std::env::temp_dir());
+    dirs
+}
+
+/// A plaintext copy of an environment for editing, only readable by the user,
+/// and overwritten before it's removed.
+struct EditFile {
+    file: NamedTempFile,
+}
+
+impl EditFile {
+    fn new(name: &std::ffi::OsStr, content: &[u8]) -> std::io::Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".dev-")
+            .suffix(name)
+            .permissions(std::fs::Permissions::from_mode(0o600));
+
+        let mut error = None;
+        for dir in edit_dirs() {
+            match builder.tempfile_in(&dir) {
+                Ok(mut file) => {
+                    file.write_all(content)?;
+                    file.flush()?;
+                    return Ok(Self { file });
+                },
+                Err(err) => error = Some(err),
+            }
+        }
+        Err(error.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+    }
+
+    fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    fn read(&self) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path())
+    }
+}
+
+impl Drop for EditFile {
+    fn drop(&mut self) {
+        // Both the file at the path, and the original file if an editor
+        // replaced it, are overwritten.
+        if let Ok(file) = std::fs::OpenOptions::new().write(true).open(self.path()) {
+            let _ = overwrite(&file);
+        }
+        let _ = overwrite(self.file.as_file());
+    }
+}
+
+/// Overwrite a file's content with zeros, without changing its length.
+fn overwrite(mut file: &File) -> std::io::Result<()> {
+    let mut remaining = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let zeros = [0; 4096];
+    while remaining > 0 {
+        let len = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_data()
+}
+
+/// Ignores SIGINT and SIGQUIT until dropped, while a child process handles
+/// them.
+struct IgnoreInterrupts {
+    int: libc::sighandler_t,
+    quit: libc::sighandler_t,
+}
+
+impl IgnoreInterrupts {
+    fn new() -> Self {
+        // SAFETY: Setting signal dispositions to SIG_IGN doesn't run any code
+        // in a signal handler.
+        unsafe {
+            Self {
+                int: libc::signal(libc::SIGINT, libc::SIG_IGN),
+                quit: libc::signal(libc::SIGQUIT, libc::SIG_IGN),
+            }
+        }
+    }
+}
+
+impl Drop for IgnoreInterrupts {
+    fn drop(&mut self) {
+        // SAFETY: The previous dispositions are restored as they were.
+        unsafe {
+            libc::signal(libc::SIGINT, self.int);
+            libc::signal(libc::SIGQUIT, self.quit);
+        }
+    }
+}
+
+// Allows modules shared with the binary to refer to this crate as `dev`.
+#[cfg(any(test, feature = "python"))]
+extern crate self as dev;
+
+#[cfg(test)]
+mod test_setup;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use crate::test_setup::*;
+
+    #[test]
+    fn test_get_repo_path_success() {
+        TestSetup::new();
+        Repo::get_repo_path().unwrap();
+    }
+
+    #[test]
+    fn test_find_dev_root() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let nested = root.join("services/api/src");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_dev_root(&nested), None);
+
+        std::fs::create_dir(root.join(".dev")).unwrap();
+        assert_eq!(find_dev_root(&nested), Some(root.to_path_buf()));
+        assert_eq!(find_dev_root(root), Some(root.to_path_buf()));
+
+        // The nearest .dev directory wins in a monorepo
+        std::fs::create_dir(root.join("services/api/.dev")).unwrap();
+        assert_eq!(find_dev_root(&nested), Some(root.join("services/api")));
+    }
+
+    #[test]
+    fn test_packages() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join(".dev")).unwrap();
+        std::fs::create_dir_all(root.join("services/api/.dev")).unwrap();
+        std::fs::write(root.join(".dev/config.toml"), "
+            [commands]
+            shell = 'nix develop -c -- \"$@\"'
+
+            [keys]
+            default = ['root-key']
+            prd = ['root-prd-key']
+
+            [packages]
+            api = 'services/api'
+            web = 'services/web'
+        ").unwrap();
+        std::fs::write(root.join("services/api/.dev/config.toml"), "
+            inherit = true
+
+            [keys]
+            default = ['api-key']
+        ").unwrap();
+
+        let repo = Repo::open(Some(root.join("services/api"))).unwrap();
+        let keys = repo.config().keys.as_ref().unwrap();
+        assert_eq!(keys["default"], vec!["api-key"]);
+        assert_eq!(keys["prd"], vec!["root-prd-key"]);
+        let shell = repo.config().commands.as_ref().unwrap().shell.as_deref();
+        assert_eq!(shell, Some("nix develop -c -- \"$@\""));
+
+        let packages = repo.packages().unwrap();
+        assert_eq!(packages.keys().collect::<Vec<_>>(), vec!["api", "web"]);
+        assert_eq!(packages["api"], root.join("services/api"));
+
+        let api = repo.package("api").unwrap();
+        assert_eq!(api.path(), root.join("services/api"));
+        assert_eq!(api.source(), RepoSource::Package);
+        assert!(matches!(repo.package("web"), Err(AppError::RepoNotFound(_))));
+        assert!(matches!(repo.package("db"), Err(AppError::UnknownPackage(_))));
+    }
+
+    #[test]
+    fn test_merge_defaults() {
+        let mut config: Config = toml::from_str("
+            editor = 'nano'
+
+            [commands]
+            start = 'make run'
+
+            [keys]
+            default = ['repo-key']
+        ").unwrap();
+        let user: Config = toml::from_str("
+            editor = 'vim'
+            default_environment = 'dev'
+            color = 'never'
+            confirm = 'always'
+
+            [commands]
+            start = 'user start'
+            shell = 'nix develop -c -- \"$@\"'
+
+            [keys]
+            default = ['user-key']
+            prd = ['user-prd-key']
+
+            [key_groups]
+            admins = ['user-key']
+
+            [environments.prd]
+            protected = false
+        ").unwrap();
+        config.merge_defaults(user);
+
+        assert_eq!(config.editor.as_deref(), Some("nano"));
+        assert_eq!(config.default_environment.as_deref(), Some("dev"));
+        assert_eq!(config.color, Some(ColorMode::Never));
+        assert_eq!(config.confirm, Some(ConfirmMode::Always));
+        let commands = config.commands.unwrap();
+        assert_eq!(commands.start.as_deref(), Some("make run"));
+        assert!(commands.shell.is_none());
+        let keys = config.keys.unwrap();
+        assert_eq!(keys["default"], vec!["repo-key"]);
+        assert!(!keys.contains_key("prd"));
+        assert!(config.key_groups.is_none());
+        assert!(config.environments.is_none());
+    }
+
+    #[test]
+    fn test_user_config_path() {
+        assert_eq!(
+            user_config_path("/home/me", None),
+            PathBuf::from("/home/me/.config/dev/config.toml"),
+        );
+        assert_eq!(
+            user_config_path("/home/me", Some("/xdg".into())),
+            PathBuf::from("/xdg/dev/config.toml"),
+        );
+        assert_eq!(
+            user_config_path("/home/me", Some("relative".into())),
+            PathBuf::from("/home/me/.config/dev/config.toml"),
+        );
+    }
+
+    #[test]
+    fn test_identity_paths() {
+        let config = Config {
+            identities: Some(vec!["~/.ssh/work".into(), "/keys/ci".into()]),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts("/repo".into(), "/home/me".into(), config);
+        assert_eq!(repo.identity_paths(), vec![
+            PathBuf::from("/home/me/.ssh/work"),
+            PathBuf::from("/keys/ci"),
+        ]);
+
+        let repo = Repo::from_parts("/repo".into(), "/home/me".into(), Config::default());
+        assert_eq!(repo.identity_paths(), vec![PathBuf::from("/home/me/.ssh/id_ed25519")]);
+        assert_eq!(repo.default_environment(), "local");
+    }
+
+    #[test]
+    fn test_open_explicit_root() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let repo = Repo::open(Some(temp_dir.path().into())).unwrap();
+        assert_eq!(repo.path(), temp_dir.path());
+        assert_eq!(repo.source(), RepoSource::Explicit);
+
+        let missing = temp_dir.path().join("missing");
+        match Repo::open(Some(missing.clone())) {
+            Err(AppError::RepoNotFound(path)) => assert_eq!(path, missing),
+            _ => panic!("Expected RepoNotFound"),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let setup = TestSetup::new();
+
+        // Encrypt "test content"
+        setup.env().encrypt(b"test content\n").unwrap();
+
+        // Decrypt the encrypted file
+        let content = setup.env().decrypt().unwrap();
+
+        // Decrypted content should be the same as the original content
+        assert_eq!(content.as_slice(), b"test content\n");
+
+        // Encrypted file should not contain the original content
+        let content = fs::read_to_string(setup.env().path()).unwrap();
+        assert!(!content.contains("test content"));
+    }
+
+    #[test]
+    fn test_decrypt_unknown_environment() {
+        let setup = TestSetup::new();
+
+        let result = setup.repo.get_environment("missing".into()).decrypt();
+
+        assert!(matches!(result, Err(AgeDecryptError::UnknownEnvironment(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn test_environment_lifecycle() {
+        let setup = TestSetup::new();
+        assert_eq!(setup.repo.environment_names().unwrap(), Vec::<String>::new());
+
+        // Create an empty environment
+        setup.env().create().unwrap();
+        assert!(setup.env().values().unwrap().is_empty());
+        assert!(matches!(setup.env().create(), Err(AppError::EnvironmentExists(_))));
+
+        // Copy it, including its values
+        setup.env().encrypt(b"ABC = 123\n").unwrap();
+        let copy = setup.repo.get_environment("copy".into());
+        setup.env().copy_to(&copy).unwrap();
+        assert_eq!(copy.values().unwrap(), setup.env().values().unwrap());
+        assert_eq!(setup.repo.environment_names().unwrap(), vec!["copy", "local"]);
+
+        // Delete the original
+        setup.env().delete().unwrap();
+        assert_eq!(setup.repo.environment_names().unwrap(), vec!["copy"]);
+        assert!(setup.env().delete().is_err());
+    }
+
+    #[test]
+    fn test_environment_config() {
+        let config: Config = toml::from_str(r#"
+            [environments.prd]
+            protected = true
+            color = "magenta"
+            banner = "PRODUCTION"
+        "#).unwrap();
+
+        let environments = config.environments.unwrap();
+        let prd = &environments["prd"];
+        assert!(prd.protected);
+        assert_eq!(prd.color, Some(Color::Magenta));
+        assert_eq!(prd.banner.as_deref(), Some("PRODUCTION"));
+
+        let result: std::result::Result<Config, _> = toml::from_str(r#"
+            [environments.prd]
+            color = "plaid"
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_write_banner() {
+        let mut setup = TestSetup::new();
+        setup.repo.config.environments = Some(BTreeMap::from([
+            ("prd".into(), EnvironmentConfig {
+                protected: true,
+                color: None,
+                banner: None,
+                read_only: false,
+                ..EnvironmentConfig::default()
+            }),
+            ("stg".into(), EnvironmentConfig {
+                protected: false,
+                color: Some(Color::Yellow),
+                banner: Some("Staging\nShared with QA".into()),
+                read_only: false,
+                ..EnvironmentConfig::default()
+            }),
+        ]));
+
+        let mut output = Vec::new();
+        setup.env().write_banner(&mut output, true).unwrap();
+        assert_eq!(&output, b"");
+
+        let mut output = Vec::new();
+        setup.repo.get_environment("prd".into()).write_banner(&mut output, false).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "=================================================\n",
+            "| You are using the protected 'prd' environment |\n",
+            "=================================================\n",
+        ));
+
+        let mut output = Vec::new();
+        setup.repo.get_environment("stg".into()).write_banner(&mut output, true).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "\x1b[1;33m==================\n",
+            "| Staging        |\n",
+            "| Shared with QA |\n",
+            "==================\x1b[0m\n",
+        ));
+    }
+
+    #[test]
+    fn test_confirm_assume_yes() {
+        let mut setup = TestSetup::new();
+        setup.repo.config.environments = Some(BTreeMap::from([
+            ("local".into(), EnvironmentConfig {
+                protected: true,
+                color: None,
+                banner: None,
+                read_only: false,
+                ..EnvironmentConfig::default()
+            }),
+        ]));
+        setup.repo.set_assume_yes(true);
+
+        setup.env().confirm().unwrap();
+    }
+
+    #[test]
+    fn test_config_load_error() {
+        let setup = TestSetup::new();
+        let path = setup.repo.path().join(".dev/config.toml");
+        std::fs::write(&path, "[commands]\nstart = 'ok'\nshell = [\n").unwrap();
+
+        let result = Config::load(&path);
+
+        match result {
+            Err(AppError::ConfigFileError { path: error_path, line, column, .. }) => {
+                assert_eq!(error_path, path);
+                assert_eq!((line, column), (4, 1));
+            },
+            _ => panic!("Expected ConfigFileError"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_recipient() {
+        let mut setup = TestSetup::new();
+        setup.repo.config.keys = Some(BTreeMap::from([
+            ("local".into(), vec!["not a key".into()]),
+        ]));
+
+        let result = setup.env().get_keys();
+
+        match result {
+            Err(err @ AppError::InvalidRecipient { .. }) => {
+                assert_eq!(err.to_string(), "Invalid SSH public key for the 'local' environment: 'not a key': invalid SSH recipient");
+                assert!(err.hint().unwrap().starts_with("Keys in the `local` list in [keys] must be"));
+            },
+            _ => panic!("Expected InvalidRecipient"),
+        }
+
+        setup.repo.config.keys = Some(BTreeMap::from([
+            ("local".into(), vec!["@backend".into()]),
+        ]));
+        setup.repo.config.key_groups = Some(BTreeMap::from([
+            ("backend".into(), vec!["not a key".into()]),
+        ]));
+        let err = setup.env().get_keys().unwrap_err();
+        assert!(err.hint().unwrap().starts_with("Keys in the `backend` list in [key_groups] must be"));
+    }
+
+    #[test]
+    fn test_key_groups() {
+        let mut setup = TestSetup::new();
+        setup.repo.config.key_groups = Some(BTreeMap::from([
+            ("backend".into(), vec![PUBLIC_KEY.trim().into()]),
+            ("ci".into(), vec![OTHER_KEY.into()]),
+        ]));
+        setup.repo.config.keys = Some(BTreeMap::from([
+            // The test key is listed twice, with a different comment.
+            ("local".into(), vec!["@backend".into(), PUBLIC_KEY.trim().replace("testkey", "laptop"), "@ci".into()]),
+            ("prd".into(), vec!["@backend".into(), "@missing".into()]),
+        ]));
+
+        assert_eq!(setup.env().get_key_strings().unwrap(), vec![PUBLIC_KEY.trim().to_string(), OTHER_KEY.into()]);
+        assert_eq!(setup.env().get_keys().unwrap().len(), 2);
+
+        let err = setup.repo.get_environment("prd".into()).get_keys().unwrap_err();
+        assert_eq!(err.to_string(), "The keys for the 'prd' environment use an undefined key group '@missing'");
+        assert!(err.hint().unwrap().contains("[key_groups]"));
+    }
+
+    #[test]
+    fn test_error_chain() {
+        let err = AppError::ConfigReadError(
+            "config.toml".into(),
+            std::io::Error::new(std::io::ErrorKind::NotFound, "missing"),
+        );
+        let mut output = Vec::new();
+
+        write_error_chain(&err, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "Failed to read config file \"config.toml\": missing\n");
+
+        // A cause is written when its message isn't part of the error's.
+        #[derive(Debug)]
+        struct Wrapper(std::io::Error);
+        impl std::fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "Failed to load")
+            }
+        }
+        impl std::error::Error for Wrapper {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+        let err = Wrapper(std::io::Error::new(std::io::ErrorKind::NotFound, "missing"));
+        let mut output = Vec::new();
+
+        write_error_chain(&err, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "Failed to load\n  caused by: missing\n");
+    }
+
+    #[test]
+    fn test_error_chain_hook() {
+        let cause = std::io::Error::new(std::io::ErrorKind::NotFound, "No such file or directory");
+        let err = AppError::HookError("post_edit".into(), CommandError::SpawnError(cause));
+        let mut output = Vec::new();
+
+        write_error_chain(&err, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "The post_edit hook failed: No such file or directory\n");
+    }
+
+    #[test]
+    fn test_key_label() {
+        assert_eq!(key_label(PUBLIC_KEY.trim()), "testkey");
+        assert_eq!(key_label("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMKcaO+SsZg1StalnVVX+nei1oqLT/ShJTleGpucGUt5"), "...T/ShJTleGpucGUt5");
+    }
+
+    #[test]
+    fn test_calculate_checksum_success() {
+        let setup = TestSetup::new();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "test content").unwrap();
+
+        let checksum = setup.env().calculate_checksum(file.path()).unwrap();
+        assert_eq!(checksum, "a1fff0ffefb9eace7230c24e50731f0a91c62f9cefdfe77121c2f607125dffae");
+    }
+
+    #[test]
+    fn test_edit_file() {
+        let file = EditFile::new("env.age.local".as_ref(), b"SECRET = 'abc'").unwrap();
+        let path = file.path().to_path_buf();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(file.read().unwrap(), b"SECRET = 'abc'");
+
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "{}", "secret".repeat(1000)).unwrap();
+
+        overwrite(file.as_file()).unwrap();
+
+        assert_eq!(fs::read(file.path()).unwrap(), vec![0; 6000]);
+    }
+
+    #[test]
+    fn test_edit_replaced_file() {
+        let setup = TestSetup::new();
+        let config = Config {
+            keys: setup.repo.config().keys.clone(),
+            // Saves the way many editors do, by replacing the file
+            editor: Some("f() { echo 'A = \"1\"' > \"$2.new\"; mv \"$2.new\" \"$2\"; }; f".into()),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(setup.repo.path().into(), setup.repo.home.clone(), config);
+        let env = repo.get_environment("local".into());
+        env.create().unwrap();
+
+        env.edit().unwrap();
+
+        assert_eq!(env.string_values().unwrap()["A"], "1");
+    }
+
+    /// A repo where the local environment has a `flags` section the test key
+    /// can decrypt, and a `payments` section it can't.
+    fn sections_repo(setup: &TestSetup, editor: Option<&str>) -> Repo {
+        let local = EnvironmentConfig {
+            sections: BTreeMap::from([
+                ("flags".into(), vec![PUBLIC_KEY.trim().into()]),
+                ("payments".into(), vec![OTHER_KEY.into()]),
+            ]),
+            ..EnvironmentConfig::default()
+        };
+        let config = Config {
+            keys: setup.repo.config().keys.clone(),
+            environments: Some(BTreeMap::from([("local".into(), local)])),
+            editor: editor.map(String::from),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(setup.repo.path().into(), setup.repo.home.clone(), config);
+        let env = repo.get_environment("local".into());
+        env.encrypt(b"A = '1'").unwrap();
+        env.section("flags").encrypt(b"FLAG = true").unwrap();
+        env.section("payments").encrypt(b"STRIPE = 'sk_live'").unwrap();
+        repo
+    }
+
+    #[test]
+    fn test_sections() {
+        let setup = TestSetup::new();
+        let repo = sections_repo(&setup, None);
+        let env = repo.get_environment("local".into());
+
+        assert_eq!(repo.environment_names().unwrap(), vec!["local"]);
+        assert!(setup.repo.path().join(".dev/env.age.local+flags").is_file());
+        let values = env.string_values().unwrap();
+        assert_eq!(values.keys().collect::<Vec<_>>(), vec!["A", "FLAG"]);
+
+        // Values stay in the section they're in, and new ones go in the
+        // environment's own file.
+        let mut values = env.values().unwrap().clone();
+        values.insert("FLAG".into(), Value::Boolean(false));
+        values.insert("B".into(), Value::String("2".into()));
+        env.set_values(&values).unwrap();
+
+        assert_eq!(env.section("flags").string_values().unwrap().keys().collect::<Vec<_>>(), vec!["FLAG"]);
+        assert_eq!(env.string_values().unwrap()["FLAG"], "false");
+        assert!(parse_values(&env.decrypt().unwrap()).unwrap().contains_key("B"));
+        assert!(env.section("payments").exists());
+    }
+
+    #[test]
+    fn test_edit_sections() {
+        let setup = TestSetup::new();
+        // Moves A into the flags section, and adds a value to it.
+        let editor = "f() { sed -i -e '/^A = /d' -e 's/^# dev:section flags$/&\\nA = \"1\"\\nNEW = \"x\"/' \"$2\"; }; f";
+        let repo = sections_repo(&setup, Some(editor));
+        let env = repo.get_environment("local".into());
+        let payments = std::fs::read(env.section("payments").path()).unwrap();
+
+        env.edit().unwrap();
+
+        let flags = env.section("flags").string_values().unwrap();
+        assert_eq!(flags.keys().collect::<Vec<_>>(), vec!["A", "FLAG", "NEW"]);
+        assert!(parse_values(&env.decrypt().unwrap()).unwrap().is_empty());
+        assert_eq!(std::fs::read(env.section("payments").path()).unwrap(), payments);
+    }
+
+    #[test]
+    fn test_edit_unknown_section() {
+        let setup = TestSetup::new();
+        let repo = sections_repo(&setup, Some("f() { echo '# dev:section payments' >> \"$2\"; }; f"));
+
+        let err = repo.get_environment("local".into()).edit().unwrap_err();
+
+        assert_eq!(err.to_string(), "Environment 'local' has no section 'payments'");
+    }
+
+    #[test]
+    fn test_edit_removed_section_marker() {
+        let setup = TestSetup::new();
+        let repo = sections_repo(&setup, Some("f() { sed -i '/# dev:section flags/d' \"$2\"; }; f"));
+        let env = repo.get_environment("local".into());
+
+        let err = env.edit().unwrap_err();
+
+        assert!(matches!(err, AppError::SectionMarkerRemoved { ref section, .. } if section == "flags"));
+        assert_eq!(env.string_values().unwrap().keys().collect::<Vec<_>>(), vec!["A", "FLAG"]);
+        assert!(env.section("flags").string_values().unwrap().contains_key("FLAG"));
+    }
+
+    #[test]
+    fn test_edit_moved_section_value() {
+        let setup = TestSetup::new();
+        let editor = "f() { printf \"A = '1'\\nFLAG = true\\n\\n# dev:section flags\\n\" > \"$2\"; }; f";
+        let mut repo = sections_repo(&setup, Some(editor));
+        repo.config.keys = Some(BTreeMap::from([
+            ("local".into(), vec![PUBLIC_KEY.trim().into(), OTHER_KEY.into()]),
+        ]));
+
+        let err = repo.get_environment("local".into()).edit().unwrap_err();
+
+        assert_eq!(err.to_string(), "'FLAG' was moved out of the 'flags' section of 'local', into a part other keys can decrypt, nothing was saved");
+
+        // Moving it to a part with the same keys is fine.
+        repo.config.keys = setup.repo.config().keys.clone();
+        repo.get_environment("local".into()).edit().unwrap();
+        assert!(repo.get_environment("local".into()).section("flags").string_values().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_join_sections() {
+        let parts = vec![
+            (None, Zeroizing::new(b"A = 1".to_vec())),
+            (Some("flags".into()), Zeroizing::new(b"B = 2".to_vec())),
+        ];
+
+        let joined = join_sections(&parts);
+
+        assert_eq!(String::from_utf8(joined.to_vec()).unwrap(), "A = 1\n\n# dev:section flags\nB = 2\n");
+        assert_eq!(join_sections(&parts[..1]).as_slice(), b"A = 1");
+    }
+
+    #[test]
+    fn test_check_name() {
+        let setup = TestSetup::new();
+        setup.env().create().unwrap();
+
+        for name in ["", "../config.toml", "prd/eu", "prd+db", "..prd"] {
+            let result = setup.repo.get_environment(name.into()).create();
+            assert!(matches!(result, Err(AppError::InvalidEnvironmentName(_))), "{:?}", name);
+        }
+        let result = setup.env().copy_to(&setup.repo.get_environment("prd+db".into()));
+        assert!(matches!(result, Err(AppError::InvalidEnvironmentName(_))));
+        setup.env().copy_to(&setup.repo.get_environment("prd.eu-west-1".into())).unwrap();
+    }
+
+    #[test]
+    fn test_copy_and_delete_sections() {
+        let setup = TestSetup::new();
+        let repo = sections_repo(&setup, None);
+        let env = repo.get_environment("local".into());
+        // Copying needs every section, which the test key can't decrypt.
+        std::fs::remove_file(env.section("payments").path()).unwrap();
+
+        let err = env.copy_to(&repo.get_environment("prd".into())).unwrap_err();
+        assert_eq!(err.to_string(), "Missing required config value 'environments.prd.sections.flags'");
+
+        env.delete().unwrap();
+        assert!(!env.section("flags").exists());
+    }
+
+    #[test]
+    fn test_run_editor_success() {
+        let setup = TestSetup::new();
+        let file = NamedTempFile::new().unwrap();
+
+        env::set_var("EDITOR", "true");
+        setup.env().run_editor(file.path()).unwrap();
+    }
+
+    #[test]
+    fn test_run_editor_failure() {
+        let setup = TestSetup::new();
+        let file = NamedTempFile::new().unwrap();
+
+        env::set_var("EDITOR", "false");
+        let result = setup.env().run_editor(file.path());
+
+        assert!(result.is_err());
+        if let Err(AppError::EditorError(CommandError::FailedError { status, .. })) = result {
+            assert!(!status.success());
+        } else {
+            panic!("Expected EditorError with FailedError");
+        }
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let old = toml::from_str("A = 1\nB = 'b'\nC = true").unwrap();
+        let new = toml::from_str("A = 1\nB = 'changed'\nD = 'new'").unwrap();
+        assert_eq!(changed_keys(&old, &new), vec!["B", "C", "D"]);
+        assert!(changed_keys(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let setup = TestSetup::new();
+        let config = Config {
+            keys: setup.repo.config().keys.clone(),
+            hooks: Some(Hooks {
+                pre_run: Some("echo \"$DEV_HOOK $DEV_ENVIRONMENT $FOO $DEV_TASK\" > hook.txt".into()),
+                pre_encrypt: Some("test \"$FOO\" != bad".into()),
+                ..Hooks::default()
+            }),
+            ..Config::default()
+        };
+        let repo = Repo::from_parts(setup.repo.path().into(), setup.repo.home.clone(), config);
+        let env = repo.get_environment("local".into());
+
+        env.set_values(&toml::from_str("FOO = 'ok'").unwrap()).unwrap();
+        env.run_hook(Hook::PreRun, vec![("DEV_TASK".into(), "migrate".into())]).unwrap();
+        let output = std::fs::read_to_string(repo.path().join("hook.txt")).unwrap();
+        assert_eq!(output, "pre_run local ok migrate\n");
+
+        // A failing pre_encrypt hook leaves the environment as it was
+        let result = env.set_values(&toml::from_str("FOO = 'bad'").unwrap());
+        assert!(matches!(result, Err(AppError::HookError(..))));
+        assert_eq!(env.string_values().unwrap()["FOO"], "ok");
+
+        // Hooks that aren't configured do nothing
+        env.run_hook(Hook::PostEdit, Vec::new()).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+//! Python bindings, so Python projects can load environments without running
+//! `dev` in a subprocess.
+//!
+//! ```python
+//! import dev
+//!
+//! values = dev.load_env("prd")
+//! dev.load_env("local", populate=True)
+//! ```
+//!
+//! Unlike the `dev` command, loading an environment doesn't disable core
+//! dumps, as that would change the limit for the whole Python process.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PySystemExit};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::Repo;
+use crate::error::AppError;
+
+create_exception!(dev, DevError, PyException, "An error raised by the dev tool.");
+
+impl From<AppError> for PyErr {
+    fn from(err: AppError) -> Self {
+        DevError::new_err(err.to_string())
+    }
+}
+
+/// Decrypt an environment's variables, finding the project the same way as
+/// the `dev` command. The environment defaults to the config's
+/// `default_environment`, or `local`. With `populate`, the variables are also added to
+/// `os.environ`, replacing existing variables only when `override` is set.
+#[pyfunction]
+#[pyo3(signature = (environment = None, *, populate = false, r#override = false))]
+fn load_env<'py>(py: Python<'py>, environment: Option<&str>, populate: bool, r#override: bool) -> PyResult<Bound<'py, PyDict>> {
+    let repo = Repo::new()?;
+    let environment = environment.unwrap_or_else(|| repo.default_environment());
+    let values = repo.get_environment(environment.into()).string_values()?;
+
+    let result = PyDict::new(py);
+    let environ = py.import("os")?.getattr("environ")?;
+    for (key, value) in &values {
+        if populate && (r#override || !environ.contains(key)?) {
+            environ.set_item(key, value)?;
+        }
+        result.set_item(key, value)?;
+    }
+
+    Ok(result)
+}
+
+/// The entry point for the `dev` script installed with the wheel. The
+/// command runs inside the interpreter, and a failure is raised as
+/// `SystemExit`, so the interpreter still shuts down normally.
+#[pyfunction]
+fn main(py: Python<'_>) -> PyResult<()> {
+    let args: Vec<String> = py.import("sys")?.getattr("argv")?.extract()?;
+    match py.detach(|| crate::cli::run(args)) {
+        0 => Ok(()),
+        code => Err(PySystemExit::new_err(code)),
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "dev")]
+fn dev_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("DevError", m.py().get_type::<DevError>())?;
+    m.add_function(wrap_pyfunction!(load_env, m)?)?;
+    m.add_function(wrap_pyfunction!(main, m)?)?;
+    Ok(())
+}
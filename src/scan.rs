@@ -0,0 +1,247 @@
+//! Searching a project's files for secret values that have been committed, or
+//! are about to be, in plaintext.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::*;
+use crate::redact::Redactor;
+use crate::Repo;
+
+/// A secret value found in plaintext.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    /// The file, relative to the project root.
+    pub path: PathBuf,
+    pub line: usize,
+    pub environment: String,
+    pub key: String,
+    /// Whether the value was found in the staged version of the file, rather
+    /// than the working tree.
+    pub staged: bool,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let staged = if self.staged { " (staged)" } else { "" };
+        write!(f, "{}:{}{}: value of {} from the '{}' environment", self.path.display(), self.line, staged, self.key, self.environment)
+    }
+}
+
+/// Finds the values of a project's environments in files.
+pub struct Scanner {
+    environments: Vec<(String, Redactor)>,
+    /// Keys whose values are meant to be public.
+    allow: Vec<String>,
+}
+
+impl Scanner {
+    pub fn new(environments: Vec<(String, Redactor)>, allow: Vec<String>) -> Self {
+        Self { environments, allow }
+    }
+
+    /// Search one file's content, line by line. Binary files are skipped.
+    pub fn scan(&self, path: &Path, content: &[u8], staged: bool) -> Vec<Finding> {
+        // The same check git uses to decide if a file is binary.
+        if content.iter().take(8000).any(|byte| *byte == 0) {
+            return Vec::new();
+        }
+
+        let mut findings = Vec::new();
+        for (index, line) in content.split(|byte| *byte == b'\n').enumerate() {
+            for (environment, redactor) in &self.environments {
+                for key in redactor.find(line) {
+                    if self.allow.iter().any(|allowed| allowed == key) {
+                        continue;
+                    }
+                    findings.push(Finding {
+                        path: path.into(),
+                        line: index + 1,
+                        environment: environment.clone(),
+                        key: key.into(),
+                        staged,
+                    });
+                }
+            }
+        }
+        findings
+    }
+}
+
+/// Encrypted environment files, which never need scanning.
+pub fn is_ignored(path: &Path) -> bool {
+    path.parent() == Some(Path::new(".dev"))
+        && path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("env.age."))
+}
+
+/// Scan the files under some paths in a project, which default to the whole
+/// project. In a git repository, this is the files git doesn't ignore, along
+/// with the staged versions of any that have changed.
+pub fn scan_repo(repo: &Repo, scanner: &Scanner, paths: &[PathBuf]) -> Result<Vec<Finding>> {
+    let root = repo.path();
+    let paths = match paths.is_empty() {
+        true => vec![root.to_path_buf()],
+        false => paths.iter().map(|path| std::path::absolute(path).unwrap_or_else(|_| path.clone())).collect(),
+    };
+
+    let mut findings = Vec::new();
+    if !is_git_repo(root) {
+        for path in walk(root, &paths)? {
+            let content = std::fs::read(root.join(&path)).map_err(|e| AppError::IoError(path.clone(), e))?;
+            findings.extend(scanner.scan(&path, &content, false));
+        }
+        return Ok(findings);
+    }
+
+    for path in git_files(root, &["ls-files", "-z", "--cached", "--others", "--exclude-standard"], &paths)? {
+        // Files deleted from the working tree are still listed until the
+        // deletion is staged.
+        match std::fs::read(root.join(&path)) {
+            Ok(content) => findings.extend(scanner.scan(&path, &content, false)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(AppError::IoError(path, e)),
+        }
+    }
+    for path in git_files(root, &["diff", "-z", "--cached", "--name-only", "--relative", "--diff-filter=ACMR"], &paths)? {
+        // `:./` reads the staged file relative to the project root, which may
+        // be below the top of the repository.
+        let mut object = OsString::from(":./");
+        object.push(&path);
+        let staged = git(root, [OsStr::new("show"), &object])?;
+        if std::fs::read(root.join(&path)).ok().as_ref() != Some(&staged) {
+            findings.extend(scanner.scan(&path, &staged, true));
+        }
+    }
+    Ok(findings)
+}
+
+fn is_git_repo(root: &Path) -> bool {
+    Command::new("git")
+        .arg("-C").arg(root)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Run git in the project root, returning its output.
+fn git<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(root: &Path, args: I) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C").arg(root)
+        .args(args)
+        .output()
+        .map_err(CommandError::SpawnError)
+        .map_err(AppError::GitError)?;
+
+    if !output.status.success() {
+        return Err(AppError::GitError(CommandError::FailedError {
+            status: output.status,
+            stderr: Some(String::from_utf8_lossy(&output.stderr).to_string()),
+        }));
+    }
+    Ok(output.stdout)
+}
+
+/// The files listed by a git command limited to some paths, relative to the
+/// project root.
+fn git_files(root: &Path, args: &[&str], paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let args = args.iter()
+        .map(OsStr::new)
+        .chain([OsStr::new("--")])
+        .chain(paths.iter().map(|path| path.as_os_str()));
+    let output = git(root, args)?;
+    let files = output.split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(OsStr::from_bytes(name)))
+        .filter(|path| !is_ignored(path))
+        .collect();
+    Ok(files)
+}
+
+/// Every file under some paths, relative to the project root, for projects
+/// that aren't in a git repository.
+fn walk(root: &Path, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = paths.to_vec();
+    while let Some(path) = pending.pop() {
+        let metadata = std::fs::symlink_metadata(&path).map_err(|e| AppError::IoError(path.clone(), e))?;
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path).map_err(|e| AppError::IoError(path.clone(), e))? {
+                let entry = entry.map_err(|e| AppError::IoError(path.clone(), e))?;
+                pending.push(entry.path());
+            }
+        } else if metadata.is_file() {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            if !is_ignored(&relative) {
+                files.push(relative);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn scanner(allow: Vec<String>) -> Scanner {
+        let values = BTreeMap::from([
+            ("API_TOKEN".into(), "tok_0123456789".into()),
+            ("PUBLIC_KEY".into(), "pk_public_value".into()),
+        ]);
+        Scanner::new(vec![("production".into(), Redactor::new(&values))], allow)
+    }
+
+    #[test]
+    fn test_scan() {
+        let content = b"first\ntoken: tok_0123456789\nkey: pk_public_value\n";
+
+        let findings = scanner(vec!["PUBLIC_KEY".into()]).scan(Path::new("fixtures/api.yaml"), content, true);
+
+        assert_eq!(findings, vec![Finding {
+            path: "fixtures/api.yaml".into(),
+            line: 2,
+            environment: "production".into(),
+            key: "API_TOKEN".into(),
+            staged: true,
+        }]);
+        assert_eq!(findings[0].to_string(), "fixtures/api.yaml:2 (staged): value of API_TOKEN from the 'production' environment");
+    }
+
+    #[test]
+    fn test_scan_skips_binary() {
+        let content = b"\0tok_0123456789";
+        assert!(scanner(Vec::new()).scan(Path::new("image.png"), content, false).is_empty());
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        assert!(is_ignored(Path::new(".dev/env.age.production")));
+        assert!(!is_ignored(Path::new(".dev/config.toml")));
+        assert!(!is_ignored(Path::new("other/.dev/env.age.production")));
+    }
+
+    #[test]
+    fn test_scan_repo() {
+        let setup = crate::test_setup::TestSetup::new();
+        let root = setup.repo.path();
+        std::fs::write(root.join("committed.txt"), "tok_0123456789\n").unwrap();
+        std::fs::write(root.join("ignored.txt"), "tok_0123456789\n").unwrap();
+        std::fs::write(root.join(".gitignore"), "ignored.txt\n").unwrap();
+        std::fs::write(root.join(".dev/env.age.production"), "tok_0123456789\n").unwrap();
+        std::fs::write(root.join("staged.txt"), "token = tok_0123456789\n").unwrap();
+        git(root, ["add", "staged.txt"]).unwrap();
+        std::fs::write(root.join("staged.txt"), "token = ${API_TOKEN}\n").unwrap();
+
+        let findings = scan_repo(&setup.repo, &scanner(Vec::new()), &[]).unwrap();
+
+        let found: Vec<String> = findings.iter().map(|finding| format!("{}:{}", finding.path.display(), finding.staged)).collect();
+        assert_eq!(found, vec!["committed.txt:false", "staged.txt:true"]);
+    }
+}
@@ -0,0 +1,226 @@
+//! Containers for decrypted values, which are overwritten in memory once
+//! they're no longer needed, so they don't linger in freed memory or core
+//! dumps.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use toml::Value;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Values that can be overwritten in memory.
+pub trait Scrub {
+    fn scrub(&mut self);
+}
+
+impl Scrub for String {
+    fn scrub(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl Scrub for Value {
+    fn scrub(&mut self) {
+        match self {
+            Value::String(value) => value.zeroize(),
+            Value::Integer(value) => value.zeroize(),
+            Value::Float(value) => value.zeroize(),
+            Value::Boolean(value) => value.zeroize(),
+            Value::Array(values) => values.iter_mut().for_each(Scrub::scrub),
+            Value::Table(table) => {
+                for (mut key, mut value) in std::mem::take(table) {
+                    key.zeroize();
+                    value.scrub();
+                }
+            },
+            // Datetimes have no heap data, and are replaced with a value that
+            // doesn't depend on the original.
+            Value::Datetime(_) => *self = Value::Boolean(false),
+        }
+    }
+}
+
+/// An environment's decrypted values by name, which are overwritten in memory
+/// when dropped. It can be used as a `BTreeMap`.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secrets<V: Scrub>(BTreeMap<String, V>);
+
+impl<V: Scrub> Secrets<V> {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<V: Scrub> Deref for Secrets<V> {
+    type Target = BTreeMap<String, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<V: Scrub> DerefMut for Secrets<V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<V: Scrub> From<BTreeMap<String, V>> for Secrets<V> {
+    fn from(values: BTreeMap<String, V>) -> Self {
+        Self(values)
+    }
+}
+
+impl<V: Scrub> FromIterator<(String, V)> for Secrets<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<'a, V: Scrub> IntoIterator for &'a Secrets<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = std::collections::btree_map::Iter<'a, String, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+// Values are never printed, in case they end up in logs.
+impl<V: Scrub> std::fmt::Debug for Secrets<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl<V: Scrub> Drop for Secrets<V> {
+    fn drop(&mut self) {
+        for (mut key, mut value) in std::mem::take(&mut self.0) {
+            key.zeroize();
+            value.scrub();
+        }
+    }
+}
+
+/// Read everything from a reader into a buffer that's overwritten when
+/// dropped. When the buffer needs to grow, the old one is overwritten too,
+/// rather than being left behind by a reallocation.
+pub fn read_secret<R: Read>(mut reader: R) -> std::io::Result<Zeroizing<Vec<u8>>> {
+    let mut buffer = Zeroizing::new(Vec::with_capacity(4096));
+    let mut chunk = Zeroizing::new([0; 4096]);
+    loop {
+        let len = match reader.read(&mut chunk[..]) {
+            Ok(0) => return Ok(buffer),
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        if buffer.len() + len > buffer.capacity() {
+            let mut grown = Zeroizing::new(Vec::with_capacity((buffer.len() + len) * 2));
+            grown.extend_from_slice(&buffer);
+            buffer = grown;
+        }
+        buffer.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// The core dump limit from before secrets were decrypted.
+static CORE_LIMIT: OnceLock<Option<libc::rlimit>> = OnceLock::new();
+
+/// Stop the process from writing core dumps, which would contain any secrets
+/// in memory, by setting the soft `RLIMIT_CORE` limit to zero. The previous
+/// limit is kept, so it can be restored for child processes.
+pub fn disable_core_dumps() {
+    CORE_LIMIT.get_or_init(|| {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: The pointers are to valid rlimit structs.
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+                return None;
+            }
+            let disabled = libc::rlimit { rlim_cur: 0, rlim_max: limit.rlim_max };
+            if libc::setrlimit(libc::RLIMIT_CORE, &disabled) != 0 {
+                return None;
+            }
+        }
+        Some(limit)
+    });
+}
+
+/// The core dump limit to restore in child processes, if it was changed by
+/// [`disable_core_dumps`].
+pub fn original_core_limit() -> Option<libc::rlimit> {
+    CORE_LIMIT.get().copied().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A value whose buffer is shared with the test, so it can be inspected
+    /// after the value has been dropped.
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Scrub for Shared {
+        fn scrub(&mut self) {
+            self.0.borrow_mut().zeroize();
+        }
+    }
+
+    #[test]
+    fn test_secrets_cleared_on_drop() {
+        let buffer = Rc::new(RefCell::new(b"hunter2".to_vec()));
+        let secrets: Secrets<Shared> = [("PASSWORD".into(), Shared(buffer.clone()))].into_iter().collect();
+        assert_eq!(buffer.borrow().as_slice(), b"hunter2");
+
+        drop(secrets);
+
+        assert!(buffer.borrow().iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_scrub_string() {
+        let mut value = String::from("hunter2");
+        let (ptr, capacity) = (value.as_ptr(), value.capacity());
+
+        value.scrub();
+
+        // The allocation is still owned by `value`, so it can be read.
+        let bytes = unsafe { std::slice::from_raw_parts(ptr, capacity) };
+        assert!(bytes.iter().all(|byte| *byte == 0));
+        assert!(value.is_empty());
+    }
+
+    #[test]
+    fn test_scrub_value() {
+        let mut value: Value = toml::from_str::<toml::Table>("
+            a = 'secret'
+            b = [1, 'two']
+            c = { d = true }
+        ").unwrap().into();
+
+        value.scrub();
+
+        assert_eq!(value, Value::Table(Default::default()));
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let content = "x".repeat(10_000);
+        let buffer = read_secret(content.as_bytes()).unwrap();
+        assert_eq!(buffer.as_slice(), content.as_bytes());
+    }
+
+    #[test]
+    fn test_debug_hides_values() {
+        let secrets: Secrets<String> = [("KEY".into(), "secret".into())].into_iter().collect();
+        assert_eq!(format!("{:?}", secrets), "{\"KEY\"}");
+    }
+}
@@ -0,0 +1,555 @@
+//! Reading files encrypted by Mozilla SOPS for age keys, so projects that
+//! already use SOPS can move to dev one environment at a time. YAML, JSON and
+//! dotenv files are supported, decrypted with the same SSH keys as
+//! environments, so the files need to be encrypted for SSH public keys.
+//!
+//! Each value is authenticated with the path of keys leading to it, and the
+//! MAC SOPS adds over all of the values is checked, so values can't be added
+//! or changed without the data key.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Nonce};
+use age::Decryptor;
+use age::armor::ArmoredReader;
+use age::ssh::Identity;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use regex::Regex;
+use sha2::{Digest, Sha512};
+use serde_json::Value as Tree;
+use toml::Value;
+use zeroize::Zeroizing;
+
+use crate::error::*;
+use crate::secrets::Secrets;
+use crate::Repo;
+
+/// The cipher SOPS encrypts values with, which uses 32 byte IVs rather than
+/// the usual 12.
+type Cipher = AesGcm<Aes256, U32>;
+
+/// The key SOPS keeps its metadata under, in the file itself.
+const METADATA_KEY: &str = "sops";
+
+/// The prefix of the metadata in dotenv files, where it's flattened.
+const DOTENV_PREFIX: &str = "sops_";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+    Yaml,
+    Json,
+    Dotenv,
+}
+
+impl Format {
+    /// The format of a file, from its name, like SOPS does.
+    fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        // Files like `.env.production` are dotenv files too.
+        if name.starts_with(".env") {
+            return Some(Self::Dotenv);
+        }
+        match name.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("yaml" | "yml") => Some(Self::Yaml),
+            Some("json") => Some(Self::Json),
+            Some("env") => Some(Self::Dotenv),
+            _ => None,
+        }
+    }
+}
+
+/// Read and decrypt a SOPS file with the user's keys.
+pub fn read(repo: &Repo, path: &Path) -> Result<Secrets<Value>> {
+    let content = std::fs::read(path).map_err(|e| AppError::IoError(path.into(), e))?;
+    let identities = repo.identities()?;
+    Ok(decrypt(path, &content, &identities)?)
+}
+
+/// Decrypt the content of a SOPS file, in the format given by its path.
+pub fn decrypt(path: &Path, content: &[u8], identities: &[Identity]) -> std::result::Result<Secrets<Value>, AgeDecryptError> {
+    let content = std::str::from_utf8(content).map_err(|_| invalid("it isn't valid UTF-8"))?;
+    if is_dotenvx(content) {
+        return Err(invalid("it was encrypted by dotenvx rather than SOPS, so use `dev env migrate --from dotenvx` instead"));
+    }
+    let format = Format::detect(path)
+        .ok_or_else(|| invalid("SOPS files must be YAML, JSON or dotenv files, ending in .yaml, .yml, .json or .env"))?;
+
+    if format == Format::Dotenv {
+        return decrypt_dotenv(content, identities);
+    }
+
+    let tree: Tree = match format {
+        Format::Yaml => serde_yaml::from_str(content).map_err(|e| invalid(&format!("it isn't valid YAML: {}", e)))?,
+        _ => serde_json::from_str(content).map_err(|e| invalid(&format!("it isn't valid JSON: {}", e)))?,
+    };
+    let Tree::Object(mut tree) = tree else {
+        return Err(invalid("it doesn't contain a mapping of names to values"));
+    };
+    let metadata = tree.shift_remove(METADATA_KEY).ok_or_else(not_sops)?;
+    if metadata.get("key_groups").is_some_and(|groups| !groups.is_null()) {
+        return Err(invalid("SOPS key groups aren't supported"));
+    }
+    let encrypted_keys = metadata.get("age")
+        .and_then(Tree::as_array)
+        .map(|keys| keys.iter().filter_map(|key| key.get("enc")?.as_str()).collect())
+        .unwrap_or_default();
+    let data_key = decrypt_data_key(encrypted_keys, identities)?;
+
+    let field = |name: &str| metadata.get(name).map(|value| match value {
+        Tree::String(value) => value.clone(),
+        value => value.to_string(),
+    });
+    let mut decrypter = Decrypter::new(&data_key, Rules::new(field)?);
+    let mut values = Secrets::new();
+    for (name, tree) in tree {
+        let mut path = vec![name.clone()];
+        if let Some(value) = decrypter.tree(tree, &mut path)? {
+            values.insert(name, value);
+        }
+    }
+    decrypter.verify(field("mac"), field("lastmodified"))?;
+    Ok(values)
+}
+
+/// Whether a file was encrypted by dotenvx, which keeps its public key in
+/// the file, and prefixes each encrypted value with `encrypted:`.
+fn is_dotenvx(content: &str) -> bool {
+    content.lines().any(|line| {
+        let line = line.trim_start();
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        line.starts_with("DOTENV_PUBLIC_KEY") || line.split_once('=')
+            .is_some_and(|(_, value)| value.trim().trim_matches(['"', '\'']).starts_with("encrypted:"))
+    })
+}
+
+/// Decrypt a dotenv file, whose values are all strings, and whose metadata is
+/// in variables named like `sops_age__list_0__map_enc`.
+fn decrypt_dotenv(content: &str, identities: &[Identity]) -> std::result::Result<Secrets<Value>, AgeDecryptError> {
+    let mut entries = Vec::new();
+    let mut metadata = BTreeMap::new();
+    for line in content.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once('=').ok_or_else(|| invalid("a line isn't a NAME=value pair"))?;
+        // SOPS escapes newlines, e.g. in the armored data keys.
+        let value = value.replace("\\n", "\n");
+        match name.strip_prefix(DOTENV_PREFIX) {
+            Some(name) => { metadata.insert(name.to_string(), value); },
+            None => entries.push((name.to_string(), value)),
+        }
+    }
+    if metadata.is_empty() {
+        return Err(not_sops());
+    }
+    let encrypted_keys = metadata.iter()
+        .filter(|(name, _)| name.starts_with("age__list_") && name.ends_with("__map_enc"))
+        .map(|(_, value)| value.as_str())
+        .collect();
+    let data_key = decrypt_data_key(encrypted_keys, identities)?;
+
+    let field = |name: &str| metadata.get(name).cloned();
+    let mut decrypter = Decrypter::new(&data_key, Rules::new(field)?);
+    let mut values = Secrets::new();
+    for (name, value) in entries {
+        let mut path = vec![name.clone()];
+        if let Some(value) = decrypter.tree(Tree::String(value), &mut path)? {
+            values.insert(name, value);
+        }
+    }
+    decrypter.verify(field("mac"), field("lastmodified"))?;
+    Ok(values)
+}
+
+/// Which values SOPS encrypted, from the settings in a file's metadata.
+/// Each setting applies to a value if it matches any key on its path.
+struct Rules {
+    unencrypted_suffix: Option<String>,
+    encrypted_suffix: Option<String>,
+    unencrypted_regex: Option<Regex>,
+    encrypted_regex: Option<Regex>,
+    /// Whether the MAC only covers encrypted values.
+    mac_only_encrypted: bool,
+}
+
+impl Rules {
+    fn new(field: impl Fn(&str) -> Option<String>) -> std::result::Result<Self, AgeDecryptError> {
+        let regex = |name: &str| field(name)
+            .map(|pattern| Regex::new(&pattern).map_err(|_| invalid(&format!("its {} isn't a valid regex", name))))
+            .transpose();
+        let mut rules = Self {
+            unencrypted_suffix: field("unencrypted_suffix"),
+            encrypted_suffix: field("encrypted_suffix"),
+            unencrypted_regex: regex("unencrypted_regex")?,
+            encrypted_regex: regex("encrypted_regex")?,
+            mac_only_encrypted: field("mac_only_encrypted").is_some_and(|value| value == "true"),
+        };
+        // Files from before these settings were recorded used SOPS's default.
+        let none = rules.unencrypted_suffix.is_none() && rules.encrypted_suffix.is_none()
+            && rules.unencrypted_regex.is_none() && rules.encrypted_regex.is_none();
+        if none {
+            rules.unencrypted_suffix = Some("_unencrypted".into());
+        }
+        Ok(rules)
+    }
+
+    /// Whether SOPS encrypts the value at a path, following the same
+    /// precedence as SOPS itself.
+    fn is_encrypted(&self, path: &[String]) -> bool {
+        let mut encrypted = true;
+        if let Some(suffix) = &self.unencrypted_suffix {
+            encrypted = !path.iter().any(|key| key.ends_with(suffix.as_str()));
+        }
+        if let Some(suffix) = &self.encrypted_suffix {
+            encrypted = path.iter().any(|key| key.ends_with(suffix.as_str()));
+        }
+        if let Some(regex) = &self.unencrypted_regex {
+            encrypted = !path.iter().any(|key| regex.is_match(key));
+        }
+        if let Some(regex) = &self.encrypted_regex {
+            encrypted = path.iter().any(|key| regex.is_match(key));
+        }
+        encrypted
+    }
+}
+
+/// Decrypts the values of a file, hashing them in order as SOPS does, so they
+/// can be checked against the file's MAC.
+struct Decrypter<'a> {
+    data_key: &'a [u8],
+    rules: Rules,
+    hash: Sha512,
+}
+
+impl<'a> Decrypter<'a> {
+    fn new(data_key: &'a [u8], rules: Rules) -> Self {
+        Self { data_key, rules, hash: Sha512::new() }
+    }
+
+    /// Decrypt a value and everything in it, converting it to TOML. Nulls,
+    /// which TOML can't represent, are left out.
+    fn tree(&mut self, tree: Tree, path: &mut Vec<String>) -> std::result::Result<Option<Value>, AgeDecryptError> {
+        let value = match tree {
+            Tree::Null => None,
+            // SOPS doesn't include the indexes of items in their paths.
+            Tree::Array(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.extend(self.tree(item, path)?);
+                }
+                Some(Value::Array(values))
+            },
+            Tree::Object(entries) => {
+                let mut table = toml::Table::new();
+                for (name, tree) in entries {
+                    path.push(name.clone());
+                    let value = self.tree(tree, path)?;
+                    path.pop();
+                    table.extend(value.map(|value| (name, value)));
+                }
+                Some(Value::Table(table))
+            },
+            leaf => self.leaf(leaf, path)?,
+        };
+        Ok(value)
+    }
+
+    /// Decrypt a single value, or check that it's one SOPS leaves in
+    /// plaintext, adding it to the MAC.
+    fn leaf(&mut self, leaf: Tree, path: &[String]) -> std::result::Result<Option<Value>, AgeDecryptError> {
+        let name = path.join(".");
+        let encrypted = self.rules.is_encrypted(path);
+        let (value, plaintext) = match leaf {
+            // SOPS leaves empty strings as they are.
+            Tree::String(value) if encrypted && value.is_empty() => (Value::String(value), Zeroizing::new(String::new())),
+            Tree::String(value) if encrypted => {
+                let associated = format!("{}:", path.join(":"));
+                match decrypt_value(&value, &name, &associated, self.data_key)? {
+                    Some(decrypted) => decrypted,
+                    None => return Ok(None),
+                }
+            },
+            _ if encrypted => return Err(invalid(&format!("the value of {} isn't encrypted", name))),
+            Tree::String(value) => (Value::String(value.clone()), Zeroizing::new(value)),
+            Tree::Bool(value) => (Value::Boolean(value), Zeroizing::new(if value { "True" } else { "False" }.into())),
+            Tree::Number(number) => match number.as_i64() {
+                Some(value) => (Value::Integer(value), Zeroizing::new(value.to_string())),
+                None => {
+                    let value = number.as_f64().unwrap_or_default();
+                    (Value::Float(value), Zeroizing::new(value.to_string()))
+                },
+            },
+            _ => return Ok(None),
+        };
+        if encrypted || !self.rules.mac_only_encrypted {
+            self.hash.update(plaintext.as_bytes());
+        }
+        Ok(Some(value))
+    }
+
+    /// Check the file's MAC, which is encrypted with its last modified time
+    /// as the associated data.
+    fn verify(self, mac: Option<String>, last_modified: Option<String>) -> std::result::Result<(), AgeDecryptError> {
+        let (Some(mac), Some(last_modified)) = (mac, last_modified) else {
+            return Err(invalid("it has no MAC"));
+        };
+        let Some((_, expected)) = decrypt_value(&mac, "the MAC", &last_modified, self.data_key)? else {
+            return Err(invalid("it has no MAC"));
+        };
+        let actual = format!("{:X}", self.hash.finalize());
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(invalid("its MAC doesn't match its values, so it was changed without SOPS"));
+        }
+        Ok(())
+    }
+}
+
+/// Decrypt the key SOPS encrypts values with, from one of the age encrypted
+/// copies in the file's metadata.
+fn decrypt_data_key(encrypted_keys: Vec<&str>, identities: &[Identity]) -> std::result::Result<Zeroizing<Vec<u8>>, AgeDecryptError> {
+    if encrypted_keys.is_empty() {
+        return Err(invalid("it isn't encrypted for any age keys"));
+    }
+    for encrypted in encrypted_keys {
+        let decryptor = Decryptor::new(ArmoredReader::new(encrypted.as_bytes()))?;
+        match decryptor.decrypt(identities.iter().map(|identity| identity as _)) {
+            Ok(mut input) => {
+                let mut data_key = Zeroizing::new(Vec::new());
+                input.read_to_end(&mut data_key)?;
+                return Ok(data_key);
+            },
+            Err(age::DecryptError::NoMatchingKeys) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(age::DecryptError::NoMatchingKeys.into())
+}
+
+/// Decrypt a value like `ENC[AES256_GCM,data:...,iv:...,tag:...,type:str]`,
+/// returning it along with its plaintext. Encrypted comments are left out.
+fn decrypt_value(value: &str, name: &str, associated: &str, data_key: &[u8]) -> std::result::Result<Option<(Value, Zeroizing<String>)>, AgeDecryptError> {
+    let Some(fields) = value.strip_prefix("ENC[AES256_GCM,").and_then(|value| value.strip_suffix(']')) else {
+        return Err(invalid(&format!("the value of {} isn't encrypted", name)));
+    };
+    let corrupt = || invalid(&format!("the value of {} can't be decrypted", name));
+    let field = |field: &str| fields.split(',')
+        .find_map(|part| part.strip_prefix(field)?.strip_prefix(':'));
+    let decode = |field_name: &str| field(field_name)
+        .and_then(|encoded| STANDARD.decode(encoded).ok())
+        .ok_or_else(corrupt);
+
+    let mut data = decode("data")?;
+    let iv = decode("iv")?;
+    data.extend(decode("tag")?);
+    if iv.len() != 32 {
+        return Err(corrupt());
+    }
+    let cipher = Cipher::new_from_slice(data_key).map_err(|_| invalid("the data key is the wrong length"))?;
+    let plaintext = cipher.decrypt(Nonce::<U32>::from_slice(&iv), Payload { msg: &data, aad: associated.as_bytes() })
+        .map_err(|_| corrupt())?;
+    let plaintext = Zeroizing::new(String::from_utf8(plaintext).map_err(|_| corrupt())?);
+
+    let value = match field("type").unwrap_or("str") {
+        "comment" => return Ok(None),
+        "int" => Value::Integer(plaintext.parse().map_err(|_| corrupt())?),
+        "float" => Value::Float(plaintext.parse().map_err(|_| corrupt())?),
+        "bool" => match plaintext.to_ascii_lowercase().as_str() {
+            "true" | "t" | "1" => Value::Boolean(true),
+            "false" | "f" | "0" => Value::Boolean(false),
+            _ => return Err(corrupt()),
+        },
+        _ => Value::String(plaintext.to_string()),
+    };
+    Ok(Some((value, plaintext)))
+}
+
+fn invalid(reason: &str) -> AgeDecryptError {
+    AgeDecryptError::InvalidFile(reason.into())
+}
+
+fn not_sops() -> AgeDecryptError {
+    invalid("it wasn't encrypted by SOPS")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_setup::{identities, TestSetup, PUBLIC_KEY};
+    use age::armor::{ArmoredWriter, Format as ArmorFormat};
+    use std::io::Write;
+    use std::str::FromStr;
+
+    const DATA_KEY: [u8; 32] = [7; 32];
+    const LAST_MODIFIED: &str = "2025-01-01T00:00:00Z";
+
+    /// Encrypt a value the way SOPS does.
+    fn encrypt_value(plaintext: &str, kind: &str, path: &str) -> String {
+        let iv = [3; 32];
+        let cipher = Cipher::new_from_slice(&DATA_KEY).unwrap();
+        let mut data = cipher.encrypt(Nonce::<U32>::from_slice(&iv), Payload { msg: plaintext.as_bytes(), aad: path.as_bytes() }).unwrap();
+        let tag = data.split_off(data.len() - 16);
+        format!("ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]", STANDARD.encode(data), STANDARD.encode(iv), STANDARD.encode(tag), kind)
+    }
+
+    /// The MAC SOPS adds over the plaintext of a file's values, in order.
+    fn mac(values: &[&str]) -> String {
+        let mut hash = Sha512::new();
+        for value in values {
+            hash.update(value);
+        }
+        encrypt_value(&format!("{:X}", hash.finalize()), "str", LAST_MODIFIED)
+    }
+
+    fn encrypted_data_key() -> String {
+        let recipient = age::ssh::Recipient::from_str(PUBLIC_KEY.trim()).unwrap();
+        let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _)).unwrap();
+        let mut content = Vec::new();
+        let output = ArmoredWriter::wrap_output(&mut content, ArmorFormat::AsciiArmor).unwrap();
+        let mut output = encryptor.wrap_output(output).unwrap();
+        output.write_all(&DATA_KEY).unwrap();
+        output.finish().and_then(|armor| armor.finish()).unwrap();
+        String::from_utf8(content).unwrap()
+    }
+
+    #[test]
+    fn test_decrypt_yaml() {
+        let content = serde_json::json!({
+            "API_TOKEN": encrypt_value("tok_123", "str", "API_TOKEN:"),
+            "PORT": encrypt_value("5432", "int", "PORT:"),
+            "DEBUG": encrypt_value("True", "bool", "DEBUG:"),
+            "db": {"password": encrypt_value("hunter2", "str", "db:password:")},
+            "REGION_unencrypted": "eu-west-1",
+            "sops": {
+                "age": [{"recipient": PUBLIC_KEY.trim(), "enc": encrypted_data_key()}],
+                "lastmodified": LAST_MODIFIED,
+                "mac": mac(&["tok_123", "5432", "True", "hunter2", "eu-west-1"]),
+                "unencrypted_suffix": "_unencrypted",
+                "version": "3.9.0",
+            },
+        });
+        let yaml = serde_yaml::to_string(&content).unwrap();
+
+        let values = decrypt(Path::new("secrets.enc.yaml"), yaml.as_bytes(), &identities()).unwrap();
+
+        assert_eq!(values["API_TOKEN"], Value::String("tok_123".into()));
+        assert_eq!(values["PORT"], Value::Integer(5432));
+        assert_eq!(values["DEBUG"], Value::Boolean(true));
+        assert_eq!(values["db"]["password"], Value::String("hunter2".into()));
+        assert_eq!(values["REGION_unencrypted"], Value::String("eu-west-1".into()));
+        assert!(!values.contains_key("sops"));
+
+        // Values are bound to their paths, so can't be moved.
+        let moved = yaml.replace("API_TOKEN", "OTHER");
+        let result = decrypt(Path::new("secrets.yaml"), moved.as_bytes(), &identities());
+        assert!(matches!(result, Err(AgeDecryptError::InvalidFile(reason)) if reason == "the value of OTHER can't be decrypted"));
+
+        // Values can't be added or changed without the data key.
+        let added = yaml.replace("REGION_unencrypted:", "LD_PRELOAD: /tmp/evil.so\nREGION_unencrypted:");
+        let result = decrypt(Path::new("secrets.yaml"), added.as_bytes(), &identities());
+        assert!(matches!(result, Err(AgeDecryptError::InvalidFile(reason)) if reason == "the value of LD_PRELOAD isn't encrypted"));
+        let changed = yaml.replace("eu-west-1", "us-east-1");
+        let result = decrypt(Path::new("secrets.yaml"), changed.as_bytes(), &identities());
+        assert!(matches!(result, Err(AgeDecryptError::InvalidFile(reason)) if reason.starts_with("its MAC doesn't match")));
+        let added = yaml.replace("REGION_unencrypted:", "PATH_unencrypted: /tmp\nREGION_unencrypted:");
+        assert!(decrypt(Path::new("secrets.yaml"), added.as_bytes(), &identities()).is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = |settings: &[(&str, &str)]| {
+            let settings: BTreeMap<String, String> = settings.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            Rules::new(|name| settings.get(name).cloned()).unwrap()
+        };
+        let path = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+
+        let default = rules(&[]);
+        assert!(default.is_encrypted(&path(&["API_TOKEN"])));
+        assert!(!default.is_encrypted(&path(&["db_unencrypted", "host"])));
+
+        let regex = rules(&[("encrypted_regex", "^(password|token)$")]);
+        assert!(regex.is_encrypted(&path(&["db", "password"])));
+        assert!(!regex.is_encrypted(&path(&["db", "host"])));
+        assert!(Rules::new(|name| (name == "unencrypted_regex").then(|| "(".into())).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_dotenv() {
+        let content = format!(
+            "# Secrets\nAPI_TOKEN={}\nsops_age__list_0__map_enc={}\nsops_lastmodified={}\nsops_mac={}\nsops_unencrypted_suffix=_unencrypted\nsops_version=3.9.0\n",
+            encrypt_value("tok_123", "str", "API_TOKEN:"),
+            encrypted_data_key().replace('\n', "\\n"),
+            LAST_MODIFIED,
+            mac(&["tok_123"]),
+        );
+
+        let values = decrypt(Path::new(".env"), content.as_bytes(), &identities()).unwrap();
+
+        assert_eq!(values.keys().collect::<Vec<_>>(), vec!["API_TOKEN"]);
+        assert_eq!(values["API_TOKEN"], Value::String("tok_123".into()));
+    }
+
+    #[test]
+    fn test_decrypt_errors() {
+        let identities = identities();
+        let error = |path: &str, content: &str| decrypt(Path::new(path), content.as_bytes(), &identities).unwrap_err().to_string();
+
+        assert_eq!(error("secrets.txt", ""), "The environment file is invalid, because SOPS files must be YAML, JSON or dotenv files, ending in .yaml, .yml, .json or .env");
+        assert_eq!(error("secrets.json", "{\"A\": 1}"), "The environment file is invalid, because it wasn't encrypted by SOPS");
+        assert_eq!(error("secrets.json", "{\"sops\": {\"kms\": []}}"), "The environment file is invalid, because it isn't encrypted for any age keys");
+        assert!(error(".env.production", "DOTENV_PUBLIC_KEY_PRODUCTION=\"03ab\"\nAPI_TOKEN=\"encrypted:BDb7\"\n").contains("--from dotenvx"));
+        assert_eq!(error(".env.production", "API_TOKEN=tok_123\n"), "The environment file is invalid, because it wasn't encrypted by SOPS");
+        assert!(error("secrets.env", "API_TOKEN=encrypted:BDb7\n").contains("encrypted by dotenvx"));
+    }
+
+    #[test]
+    fn test_environment_source() {
+        let setup = TestSetup::new();
+        let content = serde_json::json!({
+            "API_TOKEN": encrypt_value("tok_123", "str", "API_TOKEN:"),
+            "sops": {
+                "age": [{"recipient": PUBLIC_KEY.trim(), "enc": encrypted_data_key()}],
+                "lastmodified": LAST_MODIFIED,
+                "mac": mac(&["tok_123"]),
+            },
+        });
+        std::fs::write(setup.repo.path().join("secrets.json"), content.to_string()).unwrap();
+        let local = crate::EnvironmentConfig {
+            sops: Some("secrets.json".into()),
+            ..crate::EnvironmentConfig::default()
+        };
+        let config = crate::Config {
+            keys: setup.repo.config().keys.clone(),
+            environments: Some([("local".into(), local)].into()),
+            ..crate::Config::default()
+        };
+        let repo = Repo::from_parts(setup.repo.path().into(), setup.repo.home.clone(), config);
+        let env = repo.get_environment("local".into());
+
+        assert!(env.sops_path().is_some());
+        assert_eq!(env.values().unwrap()["API_TOKEN"], Value::String("tok_123".into()));
+
+        // Changing the values moves them into the environment's own file.
+        let mut values = env.values().unwrap();
+        values.insert("DEBUG".into(), Value::Boolean(true));
+        env.set_values(&values).unwrap();
+        assert!(env.exists());
+        assert!(env.sops_path().is_none());
+        assert_eq!(env.values().unwrap().keys().collect::<Vec<_>>(), vec!["API_TOKEN", "DEBUG"]);
+    }
+
+    #[test]
+    fn test_format_detect() {
+        assert_eq!(Format::detect(Path::new("secrets/dev.enc.yml")), Some(Format::Yaml));
+        assert_eq!(Format::detect(Path::new(".env")), Some(Format::Dotenv));
+        assert_eq!(Format::detect(Path::new("prod.env")), Some(Format::Dotenv));
+        assert_eq!(Format::detect(Path::new("app/.env.production")), Some(Format::Dotenv));
+        assert_eq!(Format::detect(Path::new(".env.local")), Some(Format::Dotenv));
+        assert_eq!(Format::detect(Path::new("secrets")), None);
+    }
+}